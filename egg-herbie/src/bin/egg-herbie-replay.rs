@@ -0,0 +1,505 @@
+// Replays a trace of FFI calls recorded with `EGG_HERBIE_TRACE=<path>`
+// against fresh contexts, reporting every call whose result differs from
+// the recorded one. A trace that ends in a call without a result
+// reproduces whatever crash cut it short.
+
+use egg_math::trace::{self, decode_line, Field, TRACE_VAR};
+use egg_math::*;
+
+use libc::c_void;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::str::FromStr;
+use std::{env, fs, process, ptr, slice};
+
+const USAGE: &str = "\
+usage: egg-herbie-replay <trace-file>
+
+Record a trace by running Herbie with EGG_HERBIE_TRACE=<trace-file> set.";
+
+struct Args {
+    line: usize,
+    fields: std::vec::IntoIter<Option<String>>,
+}
+
+impl Args {
+    fn next(&mut self) -> Result<Option<String>, String> {
+        self.fields
+            .next()
+            .ok_or_else(|| format!("line {}: missing argument", self.line))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.next()?
+            .ok_or_else(|| format!("line {}: unexpected null argument", self.line))
+    }
+
+    fn c_string(&mut self) -> Result<CString, String> {
+        CString::new(self.string()?).map_err(|e| format!("line {}: {}", self.line, e))
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, String> {
+        let s = self.string()?;
+        s.parse()
+            .map_err(|_| format!("line {}: expected a number, got `{}`", self.line, s))
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        let s = self.string()?;
+        s.parse()
+            .map_err(|_| format!("line {}: expected a boolean, got `{}`", self.line, s))
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        let len: usize = self.number()?;
+        (0..len).map(|_| self.string()).collect()
+    }
+}
+
+// C strings and an array of pointers to them, kept alive together
+struct CStrings {
+    _strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl CStrings {
+    fn new(items: Vec<String>) -> Result<Self, String> {
+        let strings = items
+            .into_iter()
+            .map(|s| CString::new(s).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ptrs = strings.iter().map(|s| s.as_ptr()).collect();
+        Ok(Self {
+            _strings: strings,
+            ptrs,
+        })
+    }
+
+    fn len(&self) -> u32 {
+        self.ptrs.len() as u32
+    }
+}
+
+// FFI rules built from name, left, right and direction flag quadruples
+struct Rules {
+    _strings: CStrings,
+    _rules: Vec<FFIRule>,
+    ptrs: Vec<*mut FFIRule>,
+}
+
+impl Rules {
+    fn new(items: Vec<String>, line: usize) -> Result<Self, String> {
+        if items.len() % 4 != 0 {
+            return Err(format!("line {}: malformed rule list", line));
+        }
+        let flags = items
+            .iter()
+            .skip(3)
+            .step_by(4)
+            .map(|flag| flag == "true")
+            .collect::<Vec<_>>();
+        let strings = CStrings::new(
+            items
+                .chunks(4)
+                .flat_map(|rule| rule[..3].to_vec())
+                .collect(),
+        )?;
+        let mut rules = strings
+            .ptrs
+            .chunks(3)
+            .zip(flags)
+            .map(|(rule, bidirectional)| FFIRule::new(rule[0], rule[1], rule[2], bidirectional))
+            .collect::<Vec<_>>();
+        let ptrs = rules.iter_mut().map(|rule| rule as *mut FFIRule).collect();
+        Ok(Self {
+            _strings: strings,
+            _rules: rules,
+            ptrs,
+        })
+    }
+
+    fn len(&self) -> u32 {
+        self.ptrs.len() as u32
+    }
+}
+
+unsafe fn take_string(ptr: *const c_char) -> Field {
+    let field = trace::c_str(ptr);
+    if !ptr.is_null() {
+        destroy_string(ptr as *mut c_char);
+    }
+    field
+}
+
+#[derive(Default)]
+struct Replay {
+    contexts: Vec<*mut Context>,
+    rulesets: Vec<*mut Ruleset>,
+}
+
+impl Replay {
+    fn context(&self, args: &mut Args) -> Result<*mut Context, String> {
+        let n: usize = args.number()?;
+        match self.contexts.get(n) {
+            Some(ptr) if !ptr.is_null() => Ok(*ptr),
+            _ => Err(format!("line {}: unknown context {}", args.line, n)),
+        }
+    }
+
+    fn ruleset(&self, args: &mut Args) -> Result<*mut Ruleset, String> {
+        let n: usize = args.number()?;
+        match self.rulesets.get(n) {
+            Some(ptr) if !ptr.is_null() => Ok(*ptr),
+            _ => Err(format!("line {}: unknown ruleset {}", args.line, n)),
+        }
+    }
+
+    // runs one call, returning its result if it has one
+    unsafe fn call(&mut self, call: &str, args: &mut Args) -> Result<Option<Field>, String> {
+        let result = match call {
+            "egraph_create" => {
+                self.contexts.push(egraph_create());
+                None
+            }
+            "egraph_destroy" => {
+                let n: usize = args.number()?;
+                match self.contexts.get_mut(n) {
+                    Some(ptr) if !ptr.is_null() => {
+                        egraph_destroy(*ptr);
+                        *ptr = ptr::null_mut();
+                    }
+                    _ => return Err(format!("line {}: unknown context {}", args.line, n)),
+                }
+                None
+            }
+            "egraph_add_expr" => {
+                let ctx = self.context(args)?;
+                Some(egraph_add_expr(ctx, args.c_string()?.as_ptr()).into())
+            }
+            "egraph_set_fp_exact_folding" => {
+                let ctx = self.context(args)?;
+                Some(egraph_set_fp_exact_folding(ctx, args.bool()?).into())
+            }
+            "egraph_set_prune_policy" => {
+                let ctx = self.context(args)?;
+                Some(egraph_set_prune_policy(ctx, args.number()?, args.number()?).into())
+            }
+            "egraph_set_let_output" => {
+                let ctx = self.context(args)?;
+                egraph_set_let_output(ctx, args.bool()?);
+                None
+            }
+            "egraph_set_extraction_interval" => {
+                let ctx = self.context(args)?;
+                egraph_set_extraction_interval(ctx, args.number()?);
+                None
+            }
+            // the host's callback cannot be replayed, so runs it stopped early will diverge
+            "egraph_set_iteration_callback" => None,
+            "egraph_union_exprs" => {
+                let ctx = self.context(args)?;
+                let (a, b, reason) = (args.c_string()?, args.c_string()?, args.c_string()?);
+                Some(egraph_union_exprs(ctx, a.as_ptr(), b.as_ptr(), reason.as_ptr()).into())
+            }
+            "egraph_register_rules" => {
+                let rules = Rules::new(args.list()?, args.line)?;
+                let ruleset = egraph_register_rules(rules.ptrs.as_ptr(), rules.len());
+                // like the tracer, only number the rulesets that compiled
+                if ruleset.is_null() {
+                    Some(Field::Null)
+                } else {
+                    self.rulesets.push(ruleset);
+                    Some((self.rulesets.len() as u32 - 1).into())
+                }
+            }
+            "destroy_rules" => {
+                let n: usize = args.number()?;
+                match self.rulesets.get_mut(n) {
+                    Some(ptr) if !ptr.is_null() => {
+                        destroy_rules(*ptr);
+                        *ptr = ptr::null_mut();
+                    }
+                    _ => return Err(format!("line {}: unknown ruleset {}", args.line, n)),
+                }
+                None
+            }
+            "egraph_run_rules" | "egraph_run_with_iter_limit" => {
+                let ctx = self.context(args)?;
+                let mut length = 0;
+                let mut iterations_ptr: *mut c_void = ptr::null_mut();
+                let iterations = if call == "egraph_run_rules" {
+                    let ruleset = self.ruleset(args)?;
+                    egraph_run_rules(
+                        ctx,
+                        ruleset,
+                        &mut length,
+                        &mut iterations_ptr,
+                        args.number()?,
+                        args.number()?,
+                        args.bool()?,
+                    )
+                } else {
+                    let rules = Rules::new(args.list()?, args.line)?;
+                    egraph_run_with_iter_limit(
+                        ctx,
+                        rules.ptrs.as_ptr(),
+                        rules.len(),
+                        &mut length,
+                        &mut iterations_ptr,
+                        args.number()?,
+                        args.number()?,
+                        args.bool()?,
+                    )
+                };
+                let field = trace::iterations(slice::from_raw_parts(iterations, length as usize));
+                destroy_egraphiters(iterations_ptr);
+                Some(field)
+            }
+            "egraph_run_phases" => {
+                let ctx = self.context(args)?;
+                let line = args.line;
+                let rulesets = args
+                    .list()?
+                    .into_iter()
+                    .map(
+                        |n| match n.parse::<usize>().ok().and_then(|n| self.rulesets.get(n)) {
+                            Some(ptr) if !ptr.is_null() => Ok(*ptr as *const Ruleset),
+                            _ => Err(format!("line {}: unknown ruleset {}", line, n)),
+                        },
+                    )
+                    .collect::<Result<Vec<_>, _>>()?;
+                let numbers = |items: Vec<String>| {
+                    items
+                        .iter()
+                        .map(|n| n.parse::<u32>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| format!("line {}: expected numbers", line))
+                };
+                let iter_limits = numbers(args.list()?)?;
+                let node_limits = numbers(args.list()?)?;
+                let mut length = 0;
+                let mut iterations_ptr: *mut c_void = ptr::null_mut();
+                let iterations = egraph_run_phases(
+                    ctx,
+                    rulesets.as_ptr(),
+                    iter_limits.as_ptr(),
+                    node_limits.as_ptr(),
+                    rulesets.len() as u32,
+                    &mut length,
+                    &mut iterations_ptr,
+                    args.bool()?,
+                );
+                let field = trace::iterations(slice::from_raw_parts(iterations, length as usize));
+                destroy_egraphiters(iterations_ptr);
+                Some(field)
+            }
+            "egraph_get_stop_reason" => Some(egraph_get_stop_reason(self.context(args)?).into()),
+            "egraph_get_simplest" => {
+                let ctx = self.context(args)?;
+                Some(take_string(egraph_get_simplest(
+                    ctx,
+                    args.number()?,
+                    args.number()?,
+                )))
+            }
+            "egraph_get_simplest_allowed" => {
+                let ctx = self.context(args)?;
+                let id = args.number()?;
+                let denied_ops = CStrings::new(args.list()?)?;
+                let denied_types = CStrings::new(args.list()?)?;
+                let mut cost = 0;
+                let best = egraph_get_simplest_allowed(
+                    ctx,
+                    id,
+                    denied_ops.ptrs.as_ptr(),
+                    denied_ops.len(),
+                    denied_types.ptrs.as_ptr(),
+                    denied_types.len(),
+                    &mut cost,
+                );
+                Some(match take_string(best) {
+                    Field::Str(best) => Field::Str(format!("{} {}", cost, best)),
+                    field => field,
+                })
+            }
+            "egraph_get_proof" | "egraph_is_equal" => {
+                let ctx = self.context(args)?;
+                let (expr, goal) = (args.c_string()?, args.c_string()?);
+                if call == "egraph_get_proof" {
+                    Some(take_string(egraph_get_proof(
+                        ctx,
+                        expr.as_ptr(),
+                        goal.as_ptr(),
+                    )))
+                } else {
+                    Some(egraph_is_equal(ctx, expr.as_ptr(), goal.as_ptr()).into())
+                }
+            }
+            "egraph_get_variants" => {
+                let ctx = self.context(args)?;
+                let id = args.number()?;
+                let orig = args.c_string()?;
+                Some(take_string(egraph_get_variants(ctx, id, orig.as_ptr())))
+            }
+            "egraph_find_variants" => {
+                let ctx = self.context(args)?;
+                let id = args.number()?;
+                let orig = args.c_string()?;
+                let include_ops = CStrings::new(args.list()?)?;
+                let exclude_ops = CStrings::new(args.list()?)?;
+                let include_types = CStrings::new(args.list()?)?;
+                let exclude_types = CStrings::new(args.list()?)?;
+                let mut length = 0;
+                let mut variants_ptr: *mut c_void = ptr::null_mut();
+                let variants = egraph_find_variants(
+                    ctx,
+                    id,
+                    orig.as_ptr(),
+                    include_ops.ptrs.as_ptr(),
+                    include_ops.len(),
+                    exclude_ops.ptrs.as_ptr(),
+                    exclude_ops.len(),
+                    include_types.ptrs.as_ptr(),
+                    include_types.len(),
+                    exclude_types.ptrs.as_ptr(),
+                    exclude_types.len(),
+                    args.number()?,
+                    &mut length,
+                    &mut variants_ptr,
+                );
+                let field = trace::variants(slice::from_raw_parts(variants, length as usize));
+                destroy_variants(variants_ptr);
+                Some(field)
+            }
+            "egraph_sample_terms" => {
+                let ctx = self.context(args)?;
+                Some(take_string(egraph_sample_terms(
+                    ctx,
+                    args.number()?,
+                    args.number()?,
+                    args.number()?,
+                    args.number()?,
+                    args.bool()?,
+                )))
+            }
+            "egraph_enumerate_terms" => {
+                let ctx = self.context(args)?;
+                Some(take_string(egraph_enumerate_terms(
+                    ctx,
+                    args.number()?,
+                    args.number()?,
+                    args.bool()?,
+                    args.number()?,
+                )))
+            }
+            "egraph_search_pattern" => {
+                let ctx = self.context(args)?;
+                let pattern = args.c_string()?;
+                Some(take_string(egraph_search_pattern(ctx, pattern.as_ptr())))
+            }
+            "egraph_lookup_expr" => {
+                let ctx = self.context(args)?;
+                let expr = args.c_string()?;
+                let mut id = 0;
+                let found = egraph_lookup_expr(ctx, expr.as_ptr(), &mut id);
+                Some(found.then_some(id).into())
+            }
+            "egraph_find" => {
+                let ctx = self.context(args)?;
+                Some(egraph_find(ctx, args.number()?).into())
+            }
+            "egraph_get_nodes"
+            | "egraph_get_children"
+            | "egraph_get_parents"
+            | "egraph_get_constant" => {
+                let ctx = self.context(args)?;
+                let id = args.number()?;
+                let get = match call {
+                    "egraph_get_nodes" => egraph_get_nodes,
+                    "egraph_get_children" => egraph_get_children,
+                    "egraph_get_parents" => egraph_get_parents,
+                    _ => egraph_get_constant,
+                };
+                Some(take_string(get(ctx, id)))
+            }
+            "egraph_is_unsound_detected" => {
+                Some(egraph_is_unsound_detected(self.context(args)?).into())
+            }
+            "egraph_get_times_applied" => {
+                let ctx = self.context(args)?;
+                let name = args.c_string()?;
+                Some(egraph_get_times_applied(ctx, name.as_ptr()).into())
+            }
+            "egraph_get_cost" => {
+                let ctx = self.context(args)?;
+                Some(egraph_get_cost(ctx, args.number()?, args.number()?).into())
+            }
+            "egraph_get_size" => Some(egraph_get_size(self.context(args)?).into()),
+            "egraph_get_report_json" => {
+                take_string(egraph_get_report_json(self.context(args)?));
+                None
+            }
+            _ => return Err(format!("line {}: unknown call `{}`", args.line, call)),
+        };
+
+        Ok(result)
+    }
+}
+
+fn run() -> Result<usize, String> {
+    let path = match env::args().nth(1) {
+        Some(path) if path != "-h" && path != "--help" => path,
+        _ => return Err(USAGE.into()),
+    };
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+
+    // never record the replay itself
+    env::remove_var(TRACE_VAR);
+
+    let mut replay = Replay::default();
+    let mut pending: Option<(usize, String, Option<Field>)> = None;
+    let mut calls = 0;
+    let mut divergences = 0;
+    for (i, line) in text.lines().enumerate() {
+        let mut fields = decode_line(line).into_iter();
+        let call = fields.next().flatten().unwrap_or_default();
+
+        if call == "=" {
+            let (call_line, call, replayed) = pending
+                .take()
+                .ok_or_else(|| format!("line {}: result without a call", i + 1))?;
+            let recorded = line.split_once('\t').map_or("", |(_, result)| result);
+            let replayed = replayed.map(|field| field.encode()).unwrap_or_default();
+            if recorded != replayed {
+                divergences += 1;
+                println!("; line {}: {} diverged", call_line, call);
+                println!(";   recorded: {}", recorded);
+                println!(";   replayed: {}", replayed);
+            }
+        } else {
+            let mut args = Args {
+                line: i + 1,
+                fields,
+            };
+            // Safety: every pointer passed along was created by this replay
+            let result = unsafe { replay.call(&call, &mut args)? };
+            pending = Some((i + 1, call, result));
+            calls += 1;
+        }
+    }
+
+    println!("; replayed {} calls, {} diverged", calls, divergences);
+    Ok(divergences)
+}
+
+fn main() {
+    let _ = env_logger::try_init();
+    match run() {
+        Ok(0) => (),
+        Ok(_) => process::exit(1),
+        Err(msg) => {
+            eprintln!("egg-herbie-replay: {}", msg);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Runs egg-herbie outside of Herbie so that egg problems can be reproduced
+// from a file of egg IR expressions and a file of rules.
+
+use egg_math::math::*;
+use egg_math::report::stop_reason_name;
+use egg_math::rules::load_rules;
+use egg_math::sexp::split_sexps;
+use egg_math::Context;
+
+use egg::StopReason;
+use std::{env, fs, process};
+
+const USAGE: &str = "\
+usage: egg-herbie <exprs-file> <rules-file> [options]
+
+  <exprs-file>       egg IR expressions, e.g. `(+ ($Type binary64 binary64 binary64) ($Var ($Type binary64) h0) 1)`
+  <rules-file>       one rule per line: `<name> <lhs> <rhs> [#:groups (<group> ...)] [#:when <cond>]`
+
+options:
+  --iter-limit <n>   maximum number of iterations (default: none)
+  --node-limit <n>   maximum number of e-nodes (default: 8000)
+  --groups <g,...>   only use rules in one of these groups
+  --no-const-fold    disable constant folding
+  --fp-exact         only fold constants that binary64 or binary32 computes exactly
+  --prune <policy>   enodes kept in constant classes: off, constant-leaf (default) or cheapest-<k>
+  --extract-every <n>
+                     extract after every n iterations, 0 for only the final egraph (default: 1)
+  --let              print shared subterms once, bound by `let`
+  --proofs           print a proof from each expression to its extraction";
+
+struct Options {
+    exprs_path: String,
+    rules_path: String,
+    iter_limit: usize,
+    node_limit: usize,
+    groups: Vec<String>,
+    constant_fold: bool,
+    fp_exact: bool,
+    prune: PrunePolicy,
+    extract_every: usize,
+    let_output: bool,
+    proofs: bool,
+}
+
+fn parse_limit(flag: &str, value: Option<String>) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} expects a number", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got `{}`", flag, value))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut paths = vec![];
+    let mut options = Options {
+        exprs_path: String::new(),
+        rules_path: String::new(),
+        iter_limit: u32::MAX as usize,
+        node_limit: 8000,
+        groups: vec![],
+        constant_fold: true,
+        fp_exact: false,
+        prune: PrunePolicy::ConstantLeaf,
+        extract_every: 1,
+        let_output: false,
+        proofs: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iter-limit" => options.iter_limit = parse_limit(&arg, args.next())?,
+            "--node-limit" => options.node_limit = parse_limit(&arg, args.next())?,
+            "--groups" => {
+                let groups = args.next().ok_or("--groups expects a list of groups")?;
+                options.groups = groups.split(',').map(String::from).collect();
+            }
+            "--no-const-fold" => options.constant_fold = false,
+            "--fp-exact" => options.fp_exact = true,
+            "--prune" => {
+                let policy = args.next().ok_or("--prune expects a policy")?;
+                options.prune = policy.parse()?;
+            }
+            "--extract-every" => options.extract_every = parse_limit(&arg, args.next())?,
+            "--let" => options.let_output = true,
+            "--proofs" => options.proofs = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ => paths.push(arg),
+        }
+    }
+
+    match <[String; 2]>::try_from(paths) {
+        Ok([exprs_path, rules_path]) => {
+            options.exprs_path = exprs_path;
+            options.rules_path = rules_path;
+            Ok(options)
+        }
+        Err(_) => Err("expected an expression file and a rule file".into()),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn read_exprs(path: &str) -> Result<Vec<RecExpr>, String> {
+    let text = read_file(path)?;
+    split_sexps(&text)
+        .map_err(|e| format!("{}: {}", path, e))?
+        .into_iter()
+        .map(|expr| {
+            expr.parse()
+                .map_err(|e| format!("{}: bad expression `{}`: {}", path, expr, e))
+        })
+        .collect()
+}
+
+fn read_rules(path: &str, groups: &[String]) -> Result<Vec<Rewrite>, String> {
+    let text = read_file(path)?;
+    let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+    load_rules(&text, &groups).map_err(|e| format!("{}:{}: {}", path, e.line, e.message))
+}
+
+fn run() -> Result<(), String> {
+    let options = parse_args().map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    let exprs = read_exprs(&options.exprs_path)?;
+    let rules = read_rules(&options.rules_path, &options.groups)?;
+
+    let mut context = Context::new();
+    context.set_fp_exact_folding(options.fp_exact);
+    context.set_prune_policy(options.prune);
+    context.set_extraction_interval(options.extract_every);
+    context.set_let_output(options.let_output);
+    let roots: Vec<_> = exprs.iter().map(|expr| context.add_expr(expr)).collect();
+    context.run(
+        &rules,
+        options.iter_limit,
+        options.node_limit,
+        options.constant_fold,
+    );
+
+    let runner = context.runner();
+    let stop_reason = stop_reason_name(&runner.stop_reason).unwrap_or("none");
+    match &runner.stop_reason {
+        Some(StopReason::Other(msg)) => println!("; stop reason: {} ({})", stop_reason, msg),
+        _ => println!("; stop reason: {}", stop_reason),
+    }
+    println!(
+        "; {} iterations, {} nodes, {} classes",
+        runner.iterations.len(),
+        runner.egraph.total_number_of_nodes(),
+        runner.egraph.number_of_classes()
+    );
+
+    for (i, (expr, root)) in exprs.iter().zip(roots).enumerate() {
+        let Some(ext) = context.get_simplest(root, usize::MAX) else {
+            println!("; expression {}, no sound extraction", i);
+            println!("{}", context.format_expr(expr));
+            continue;
+        };
+        let (best, cost) = (ext.best.clone(), ext.cost);
+        println!("; expression {}, cost {}", i, cost);
+        println!("{}", context.format_expr(&best));
+        if options.proofs {
+            println!("; proof");
+            println!("; {}", context.get_proof(expr, &best));
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let _ = env_logger::try_init();
+    if let Err(msg) = run() {
+        eprintln!("egg-herbie: {}", msg);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,807 @@
+use egg::{AstSize, CostFunction, Extractor, Id, Language, Searcher, Var};
+use indexmap::{IndexMap, IndexSet};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::math::*;
+
+// Orders terms by their root operator, then by number of children,
+// then by their children from left to right. Equal only for identical terms.
+pub fn compare_terms(a: &RecExpr, b: &RecExpr) -> Ordering {
+    fn compare_at(a: &RecExpr, i: Id, b: &RecExpr, j: Id) -> Ordering {
+        let (x, y) = (&a[i], &b[j]);
+        x.to_string()
+            .cmp(&y.to_string())
+            .then_with(|| x.len().cmp(&y.len()))
+            .then_with(|| {
+                x.children()
+                    .iter()
+                    .zip(y.children())
+                    .map(|(&ci, &cj)| compare_at(a, ci, b, cj))
+                    .find(|o| o.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            })
+    }
+
+    let root = |e: &RecExpr| Id::from(e.as_ref().len() - 1);
+    compare_at(a, root(a), b, root(b))
+}
+
+// Like `egg::Extractor`, except that among equally cheap e-nodes it picks
+// the one giving the smallest term under `compare_terms`, so the result
+// never depends on e-class ids or the order of e-nodes in a class.
+// Terms are compared through the e-node chosen for each class, and built
+// only once asked for, so shared classes are never expanded.
+pub struct DeterministicExtractor<'a, CF: CostFunction<Math>> {
+    egraph: &'a EGraph,
+    costs: Extractor<'a, CF, Math, ConstantFold>,
+    cost_fn: RefCell<CF>,
+    // the chosen e-node of each canonical class, `None` if none fits
+    choices: RefCell<HashMap<Id, Option<Math>>>,
+    comparisons: RefCell<HashMap<(Id, Id), Ordering>>,
+}
+
+impl<'a, CF: CostFunction<Math> + Clone> DeterministicExtractor<'a, CF> {
+    pub fn new(egraph: &'a EGraph, cost_fn: CF) -> Self {
+        Self {
+            egraph,
+            costs: Extractor::new(egraph, cost_fn.clone()),
+            cost_fn: RefCell::new(cost_fn),
+            choices: Default::default(),
+            comparisons: Default::default(),
+        }
+    }
+
+    pub fn find_best_cost(&self, id: Id) -> CF::Cost {
+        self.costs.find_best_cost(id)
+    }
+
+    pub fn find_best(&self, id: Id) -> (CF::Cost, RecExpr) {
+        let mut expr = RecExpr::default();
+        self.build(id, &mut expr, &mut HashMap::new());
+        (self.find_best_cost(id), expr)
+    }
+
+    // adds the best term of `id` to `expr`, once per class
+    fn build(&self, id: Id, expr: &mut RecExpr, built: &mut HashMap<Id, Id>) -> Id {
+        let id = self.egraph.find(id);
+        if let Some(&built_id) = built.get(&id) {
+            return built_id;
+        }
+
+        let built_id = match self.choice(id) {
+            Some(node) => {
+                let node = node.map_children(|child| self.build(child, expr, built));
+                expr.add(node)
+            }
+            // no e-node fits when every term is infinitely expensive
+            None => {
+                let (_, term) = self.costs.find_best(id);
+                let offset = expr.as_ref().len();
+                for node in term.as_ref() {
+                    expr.add(
+                        node.clone()
+                            .map_children(|child| Id::from(usize::from(child) + offset)),
+                    );
+                }
+                Id::from(expr.as_ref().len() - 1)
+            }
+        };
+        built.insert(id, built_id);
+        built_id
+    }
+
+    fn choice(&self, id: Id) -> Option<Math> {
+        let id = self.egraph.find(id);
+        if let Some(choice) = self.choices.borrow().get(&id) {
+            return choice.clone();
+        }
+
+        let best_cost = self.find_best_cost(id);
+        let mut best: Option<&Math> = None;
+        for node in &self.egraph[id].nodes {
+            let cost = self
+                .cost_fn
+                .borrow_mut()
+                .cost(node, |child| self.find_best_cost(child));
+            // only follow children that are strictly cheaper,
+            // so the recursion cannot go around a cycle
+            if cost != best_cost || node.any(|child| self.find_best_cost(child) >= best_cost) {
+                continue;
+            }
+
+            if best.map_or(true, |best| self.compare_nodes(node, best).is_lt()) {
+                best = Some(node);
+            }
+        }
+
+        let best = best.cloned();
+        self.choices.borrow_mut().insert(id, best.clone());
+        best
+    }
+
+    // `compare_terms` on the terms chosen for two e-nodes
+    pub(crate) fn compare_nodes(&self, x: &Math, y: &Math) -> Ordering {
+        x.to_string()
+            .cmp(&y.to_string())
+            .then_with(|| x.len().cmp(&y.len()))
+            .then_with(|| {
+                x.children()
+                    .iter()
+                    .zip(y.children())
+                    .map(|(&a, &b)| self.compare_classes(a, b))
+                    .find(|o| o.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            })
+    }
+
+    // `compare_terms` on the terms chosen for two classes, memoized
+    fn compare_classes(&self, a: Id, b: Id) -> Ordering {
+        let (a, b) = (self.egraph.find(a), self.egraph.find(b));
+        if a == b {
+            return Ordering::Equal;
+        }
+        if let Some(&ordering) = self.comparisons.borrow().get(&(a, b)) {
+            return ordering;
+        }
+
+        let ordering = match (self.choice(a), self.choice(b)) {
+            (Some(x), Some(y)) => self.compare_nodes(&x, &y),
+            // only classes with no finite term lack a choice, and
+            // those are never children of a chosen e-node
+            _ => a.cmp(&b),
+        };
+        self.comparisons.borrow_mut().insert((a, b), ordering);
+        ordering
+    }
+}
+
+// an alternative term for an e-class, one per e-node
+pub struct Variant {
+    pub expr: RecExpr,
+    pub cost: usize,
+    pub op: String,
+}
+
+// Restricts which variants are returned.
+// Empty `include_*` lists allow everything. Types are matched
+// like those of a `DenyList`, see `type_classes`.
+#[derive(Default)]
+pub struct VariantFilter {
+    pub include_ops: Vec<String>,
+    pub exclude_ops: Vec<String>,
+    pub include_types: Vec<String>,
+    pub exclude_types: Vec<String>,
+    pub max_variants: Option<usize>,
+}
+
+impl VariantFilter {
+    fn allows_op(&self, op: &str) -> bool {
+        (self.include_ops.is_empty() || self.include_ops.iter().any(|o| o == op))
+            && !self.exclude_ops.iter().any(|o| o == op)
+    }
+}
+
+// Extracts one variant for each e-node of `id` whose head differs from
+// the head of `orig` and that `keep` accepts, in e-node order.
+fn variants_where(
+    egraph: &EGraph,
+    id: Id,
+    orig: &RecExpr,
+    mut keep: impl FnMut(&Math) -> bool,
+) -> Vec<Variant> {
+    let head_node = &orig.as_ref()[orig.as_ref().len() - 1];
+    let extractor = DeterministicExtractor::new(egraph, AltCost::new(egraph));
+    let mut cost_fn = AltCost::new(egraph);
+    let mut cache: IndexMap<Id, (usize, RecExpr)> = Default::default();
+
+    let mut variants = vec![];
+    for n in &egraph[id].nodes {
+        // assuming same ops in an eclass cannot
+        // have different precisions
+        if n.matches(head_node) || !keep(n) {
+            continue;
+        }
+
+        // extract if not in cache
+        n.for_each(|id| {
+            cache.entry(id).or_insert_with(|| extractor.find_best(id));
+        });
+
+        let expr = n.join_recexprs(|id| cache[&id].1.as_ref());
+        let cost = cost_fn.cost(n, |id| cache[&id].0);
+        variants.push(Variant {
+            expr,
+            cost,
+            op: n.to_string(),
+        });
+    }
+
+    variants
+}
+
+// Every variant of `id`, one per e-node and possibly repeating terms.
+// They are ordered by term: e-node order depends on e-class ids,
+// so it would change with the order the egraph was built in.
+pub fn node_variants(egraph: &EGraph, id: Id, orig: &RecExpr) -> Vec<Variant> {
+    let mut variants = variants_where(egraph, id, orig, |_| true);
+    variants.sort_by(|a, b| compare_terms(&a.expr, &b.expr));
+    variants
+}
+
+// Like `node_variants` but only those `filter` allows,
+// cheapest first and without duplicate terms.
+pub fn find_variants(
+    egraph: &EGraph,
+    id: Id,
+    orig: &RecExpr,
+    filter: &VariantFilter,
+) -> Vec<Variant> {
+    let included = type_classes(egraph, &filter.include_types);
+    let excluded = type_classes(egraph, &filter.exclude_types);
+    let allows_type = |n: &Math| {
+        // the first child of an operator is its `$Type` signature
+        let sig = n.children().first().map(|&sig| egraph.find(sig));
+        let matches = |sigs: &HashSet<Id>| sig.map_or(false, |sig| sigs.contains(&sig));
+        (filter.include_types.is_empty() || matches(&included)) && !matches(&excluded)
+    };
+
+    let mut seen: IndexSet<String> = Default::default();
+    let mut variants: Vec<Variant> = variants_where(egraph, id, orig, |n| {
+        filter.allows_op(&n.to_string()) && allows_type(n)
+    })
+    .into_iter()
+    .filter(|v| seen.insert(v.expr.to_string()))
+    .collect();
+
+    // equally cheap variants are ordered by term, not by e-node order
+    variants.sort_by(|a, b| {
+        a.cost
+            .cmp(&b.cost)
+            .then_with(|| compare_terms(&a.expr, &b.expr))
+    });
+    if let Some(max) = filter.max_variants {
+        variants.truncate(max);
+    }
+
+    variants
+}
+
+// Best term of `id` that avoids everything on the deny list,
+// or `None` if every term of `id` uses something denied.
+// Fractional powers cost `FRACTIONAL_POW_PENALTY` here rather than `usize::MAX`,
+// so that they are never mistaken for denied terms.
+pub fn find_best_allowed(egraph: &EGraph, id: Id, deny: &DenyList) -> Option<(usize, RecExpr)> {
+    let cost_fn = AltCost::with_deny_list(egraph, deny).with_fractional_pow_penalty();
+    let extractor = DeterministicExtractor::new(egraph, cost_fn);
+    let (cost, best) = extractor.find_best(id);
+    if cost == usize::MAX {
+        None
+    } else {
+        Some((cost, best))
+    }
+}
+
+// SplitMix64, small and good enough to make sampling depend only on the seed
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct Sampler<'a> {
+    egraph: &'a EGraph,
+    sizes: Extractor<'a, AstSize, Math, ConstantFold>,
+    // cost function and best costs, only when weighting by cost
+    costs: Option<(AltCost<'a>, Extractor<'a, AltCost<'a>, Math, ConstantFold>)>,
+    // orders the e-nodes of a class independently of e-class ids
+    order: DeterministicExtractor<'a, AltCost<'a>>,
+    rng: SplitMix64,
+}
+
+impl<'a> Sampler<'a> {
+    // smallest AST size of any term rooted at `node`
+    fn min_size(&self, node: &Math) -> usize {
+        node.fold(1, |sum, id| {
+            usize::saturating_add(sum, self.sizes.find_best_cost(id))
+        })
+    }
+
+    // cheaper terms are more likely, and terms that `AltCost` rules out,
+    // such as fractional powers, are never picked unless nothing else fits
+    fn weight(&mut self, node: &Math) -> f64 {
+        match &mut self.costs {
+            None => 1.0,
+            Some((cost_fn, extractor)) => {
+                let cost = cost_fn.cost(node, |id| extractor.find_best_cost(id));
+                if cost == usize::MAX {
+                    0.0
+                } else {
+                    1.0 / cost as f64
+                }
+            }
+        }
+    }
+
+    fn choose(&mut self, candidates: &[(&'a Math, f64)]) -> &'a Math {
+        let total: f64 = candidates.iter().map(|(_, w)| w).sum();
+        if total <= 0.0 {
+            let i = (self.rng.next_u64() % candidates.len() as u64) as usize;
+            return candidates[i].0;
+        }
+
+        let mut r = self.rng.next_f64() * total;
+        for &(node, w) in candidates {
+            if r < w {
+                return node;
+            }
+            r -= w;
+        }
+        candidates.last().unwrap().0
+    }
+
+    // adds a random term of `id` of at most `budget` nodes to `expr`,
+    // returning its root and size
+    fn sample(&mut self, id: Id, budget: usize, expr: &mut RecExpr) -> (Id, usize) {
+        let egraph = self.egraph;
+        let mut fitting: Vec<&'a Math> = egraph[id]
+            .nodes
+            .iter()
+            .filter(|n| self.min_size(n) <= budget)
+            .collect();
+        // e-node order depends on e-class ids, which the seed does not fix
+        fitting.sort_by(|x, y| self.order.compare_nodes(x, y));
+        let candidates: Vec<(&'a Math, f64)> =
+            fitting.into_iter().map(|n| (n, self.weight(n))).collect();
+
+        let mut node = self.choose(&candidates).clone();
+        // budget left over once every child has its smallest term
+        let mut slack = budget - self.min_size(&node);
+        let mut size = 1;
+        for child in node.children_mut() {
+            let min = self.sizes.find_best_cost(*child);
+            let (new_child, child_size) = self.sample(*child, min + slack, expr);
+            slack -= child_size - min;
+            size += child_size;
+            *child = new_child;
+        }
+
+        (expr.add(node), size)
+    }
+}
+
+// Draws `n` random terms of `id` with at most `max_size` nodes each,
+// optionally preferring cheaper terms. The same seed always gives the same terms,
+// however the egraph was built.
+pub fn sample_terms(
+    egraph: &EGraph,
+    id: Id,
+    n: usize,
+    seed: u64,
+    max_size: usize,
+    weighted: bool,
+) -> Vec<RecExpr> {
+    let mut sampler = Sampler {
+        egraph,
+        sizes: Extractor::new(egraph, AstSize),
+        costs: weighted.then(|| {
+            (
+                AltCost::new(egraph),
+                Extractor::new(egraph, AltCost::new(egraph)),
+            )
+        }),
+        order: DeterministicExtractor::new(egraph, AltCost::new(egraph)),
+        rng: SplitMix64(seed),
+    };
+
+    if sampler.sizes.find_best_cost(id) > max_size {
+        return vec![];
+    }
+
+    (0..n)
+        .map(|_| {
+            let mut expr = RecExpr::default();
+            sampler.sample(id, max_size, &mut expr);
+            expr
+        })
+        .collect()
+}
+
+// limit on the terms returned by `EnumerateTerms::enumerate_terms`
+#[derive(Clone, Copy, Debug)]
+pub enum TermBound {
+    // at most this many nodes
+    Size(usize),
+    // at most this many levels
+    Depth(usize),
+}
+
+pub trait EnumerateTerms {
+    // Every distinct term of `id` within `bound`, cheapest first.
+    // `max_terms` caps both the result and every intermediate table.
+    fn enumerate_terms(
+        &self,
+        id: Id,
+        bound: TermBound,
+        max_terms: Option<usize>,
+    ) -> Vec<(usize, RecExpr)>;
+}
+
+impl EnumerateTerms for EGraph {
+    fn enumerate_terms(
+        &self,
+        id: Id,
+        bound: TermBound,
+        max_terms: Option<usize>,
+    ) -> Vec<(usize, RecExpr)> {
+        let mut enumerator = Enumerator {
+            egraph: self,
+            cost_fn: AltCost::new(self),
+            by_depth: matches!(bound, TermBound::Depth(_)),
+            max_terms,
+            memo: Default::default(),
+        };
+
+        let mut terms: Vec<(usize, RecExpr)> = match bound {
+            TermBound::Depth(depth) => enumerator.terms(id, depth).to_vec(),
+            TermBound::Size(size) => (1..=size)
+                .flat_map(|s| enumerator.terms(id, s).to_vec())
+                .collect(),
+        };
+        terms.sort_by(compare_costed_terms);
+        if let Some(max) = max_terms {
+            terms.truncate(max);
+        }
+
+        terms
+    }
+}
+
+type Terms = Rc<Vec<(usize, RecExpr)>>;
+
+// cheapest first, then by `compare_terms`
+fn compare_costed_terms(a: &(usize, RecExpr), b: &(usize, RecExpr)) -> Ordering {
+    a.0.cmp(&b.0).then_with(|| compare_terms(&a.1, &b.1))
+}
+
+struct Enumerator<'a> {
+    egraph: &'a EGraph,
+    cost_fn: AltCost<'a>,
+    by_depth: bool,
+    max_terms: Option<usize>,
+    memo: HashMap<(Id, usize), Terms>,
+}
+
+// every way to split `n` nodes among `k` children, each getting at least one
+fn compositions(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return if n == 0 { vec![vec![]] } else { vec![] };
+    }
+
+    let mut result = vec![];
+    for first in 1..=n.saturating_sub(k - 1) {
+        for mut rest in compositions(n - first, k - 1) {
+            rest.insert(0, first);
+            result.push(rest);
+        }
+    }
+    result
+}
+
+// copies `part` onto the end of `expr`, returning the id of its root
+fn append_recexpr(expr: &mut RecExpr, part: &RecExpr) -> Id {
+    let offset = expr.as_ref().len();
+    let mut root = Id::from(offset);
+    for node in part.as_ref() {
+        let mut node = node.clone();
+        node.update_children(|child| Id::from(usize::from(child) + offset));
+        root = expr.add(node);
+    }
+    root
+}
+
+impl<'a> Enumerator<'a> {
+    // terms of `id` with exactly `bound` nodes, or at most `bound` levels
+    fn terms(&mut self, id: Id, bound: usize) -> Terms {
+        let id = self.egraph.find(id);
+        if let Some(terms) = self.memo.get(&(id, bound)) {
+            return terms.clone();
+        }
+
+        let mut terms = vec![];
+        if bound > 0 {
+            let egraph = self.egraph;
+            for node in &egraph[id].nodes {
+                let children = node.children();
+                let child_bounds = if self.by_depth {
+                    vec![vec![bound - 1; children.len()]]
+                } else {
+                    compositions(bound - 1, children.len())
+                };
+
+                for bounds in child_bounds {
+                    let child_terms: Vec<Terms> = children
+                        .iter()
+                        .zip(bounds)
+                        .map(|(&child, b)| self.terms(child, b))
+                        .collect();
+                    self.combine(node, &child_terms, &mut terms);
+                }
+            }
+
+            terms.sort_by(compare_costed_terms);
+            if let Some(max) = self.max_terms {
+                terms.truncate(max);
+            }
+        }
+
+        let terms = Rc::new(terms);
+        self.memo.insert((id, bound), terms.clone());
+        terms
+    }
+
+    // adds `node` applied to every combination of child terms
+    fn combine(&mut self, node: &Math, child_terms: &[Terms], out: &mut Vec<(usize, RecExpr)>) {
+        if child_terms.iter().any(|terms| terms.is_empty()) {
+            return;
+        }
+
+        let mut choice = vec![0; child_terms.len()];
+        loop {
+            let mut expr = RecExpr::default();
+            let mut term = node.clone();
+            for ((child, terms), &i) in term.children_mut().iter_mut().zip(child_terms).zip(&choice)
+            {
+                *child = append_recexpr(&mut expr, &terms[i].1);
+            }
+            expr.add(term);
+
+            // children are folded in order, so hand out their costs by position
+            let mut position = 0;
+            let cost = self.cost_fn.cost(node, |_| {
+                let cost = child_terms[position][choice[position]].0;
+                position += 1;
+                cost
+            });
+            out.push((cost, expr));
+
+            // advance to the next combination
+            let mut k = 0;
+            while k < choice.len() {
+                choice[k] += 1;
+                if choice[k] < child_terms[k].len() {
+                    break;
+                }
+                choice[k] = 0;
+                k += 1;
+            }
+            if k == choice.len() {
+                return;
+            }
+        }
+    }
+}
+
+// a match of a pattern, with each variable bound to the best term of its eclass
+pub struct PatternMatch {
+    pub eclass: Id,
+    pub bindings: Vec<(Var, RecExpr)>,
+}
+
+// Every match of `pattern` in the egraph, one per substitution.
+pub fn search_pattern(egraph: &EGraph, pattern: &Pattern) -> Vec<PatternMatch> {
+    let extractor = DeterministicExtractor::new(egraph, AltCost::new(egraph));
+    let vars = pattern.vars();
+
+    pattern
+        .search(egraph)
+        .into_iter()
+        .flat_map(|matches| {
+            let eclass = matches.eclass;
+            matches
+                .substs
+                .into_iter()
+                .map(|subst| PatternMatch {
+                    eclass,
+                    bindings: vars
+                        .iter()
+                        .map(|&var| (var, extractor.find_best(subst[var]).1))
+                        .collect(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIG1: &str = "($Type binary64 binary64)";
+    const SIG2: &str = "($Type binary64 binary64 binary64)";
+
+    // puts every expression in one eclass, adding them in the given order
+    fn union_all(exprs: &[String]) -> (EGraph, Id) {
+        let mut egraph = EGraph::default();
+        let ids: Vec<Id> = exprs
+            .iter()
+            .map(|e| egraph.add_expr(&e.parse().unwrap()))
+            .collect();
+        for &id in &ids[1..] {
+            egraph.union(ids[0], id);
+        }
+        egraph.rebuild();
+        let root = egraph.find(ids[0]);
+        (egraph, root)
+    }
+
+    // every rotation of `exprs`, forwards and backwards
+    fn orders(exprs: &[String]) -> Vec<Vec<String>> {
+        let mut orders = vec![];
+        for mut order in [exprs.to_vec(), exprs.iter().rev().cloned().collect()] {
+            for _ in 0..order.len() {
+                order.rotate_left(1);
+                orders.push(order.clone());
+            }
+        }
+        orders
+    }
+
+    fn assert_best(exprs: &[String], expected: &str) {
+        for order in orders(exprs) {
+            let (egraph, root) = union_all(&order);
+            let extractor = DeterministicExtractor::new(&egraph, AltCost::new(&egraph));
+            let (_, best) = extractor.find_best(root);
+            assert_eq!(best.to_string(), expected, "adding {:?}", order);
+        }
+    }
+
+    #[test]
+    fn ties_break_by_operator() {
+        let exprs = [
+            format!("(- {} x y)", SIG2),
+            format!("(+ {} x y)", SIG2),
+            format!("(* {} x y)", SIG2),
+        ];
+        assert_best(&exprs, &format!("(* {} x y)", SIG2));
+    }
+
+    #[test]
+    fn ties_break_by_children() {
+        let exprs = [
+            format!("(+ {} y (neg {} x))", SIG2, SIG1),
+            format!("(+ {} (neg {} x) y)", SIG2, SIG1),
+            format!("(+ {} (neg {} y) x)", SIG2, SIG1),
+        ];
+        assert_best(&exprs, &format!("(+ {} (neg {} x) y)", SIG2, SIG1));
+    }
+
+    #[test]
+    fn cheaper_terms_beat_tie_breaks() {
+        let exprs = [
+            format!("(sqrt {} x)", SIG1),
+            format!("(neg {} (neg {} x))", SIG1, SIG1),
+        ];
+        assert_best(&exprs, &format!("(sqrt {} x)", SIG1));
+    }
+
+    #[test]
+    fn equally_cheap_variants_are_ordered_by_term() {
+        let exprs = [
+            format!("(/ {} x y)", SIG2),
+            format!("(- {} x y)", SIG2),
+            format!("(+ {} x y)", SIG2),
+            format!("(* {} x y)", SIG2),
+        ];
+        let orig: RecExpr = exprs[0].parse().unwrap();
+        let expected: Vec<String> = exprs[1..].iter().rev().cloned().collect();
+        let terms = |variants: Vec<Variant>| -> Vec<String> {
+            variants.into_iter().map(|v| v.expr.to_string()).collect()
+        };
+        for order in orders(&exprs) {
+            let (egraph, root) = union_all(&order);
+            let found = find_variants(&egraph, root, &orig, &Default::default());
+            assert_eq!(terms(found), expected, "adding {:?}", order);
+            let all = node_variants(&egraph, root, &orig);
+            assert_eq!(terms(all), expected, "adding {:?}", order);
+        }
+    }
+
+    #[test]
+    fn fractional_powers_are_penalized_only_when_extracting_allowed_terms() {
+        let (egraph, root) = union_all(&[format!("(pow {} x 1/3)", SIG2)]);
+        let extractor = DeterministicExtractor::new(&egraph, AltCost::new(&egraph));
+        assert_eq!(extractor.find_best(root).0, usize::MAX);
+
+        let (cost, _) = find_best_allowed(&egraph, root, &Default::default()).unwrap();
+        assert!(
+            (FRACTIONAL_POW_PENALTY..usize::MAX).contains(&cost),
+            "{}",
+            cost
+        );
+        let deny = DenyList {
+            ops: vec!["pow".into()],
+            types: vec![],
+        };
+        assert!(find_best_allowed(&egraph, root, &deny).is_none());
+    }
+
+    #[test]
+    fn samples_depend_only_on_the_seed() {
+        let exprs = [
+            format!("(+ {} x (neg {} y))", SIG2, SIG1),
+            format!("(- {} x y)", SIG2),
+            format!("(+ {} (neg {} y) x)", SIG2, SIG1),
+            format!("(* {} x (sqrt {} y))", SIG2, SIG1),
+        ];
+        for weighted in [false, true] {
+            let sample = |order: &[String]| {
+                let (egraph, root) = union_all(order);
+                let terms = sample_terms(&egraph, root, 20, 7, 10, weighted);
+                terms.iter().map(|t| t.to_string()).collect::<Vec<_>>()
+            };
+            let expected = sample(&exprs);
+            assert_eq!(expected.len(), 20);
+            for order in orders(&exprs) {
+                assert_eq!(sample(&order), expected, "adding {:?}", order);
+            }
+        }
+    }
+
+    // `x` and its negation in one class, so the class contains itself
+    fn cyclic_class() -> (EGraph, Id) {
+        union_all(&["x".to_string(), format!("(neg {} x)", SIG1)])
+    }
+
+    fn strings(terms: &[(usize, RecExpr)]) -> Vec<String> {
+        terms.iter().map(|(_, t)| t.to_string()).collect()
+    }
+
+    #[test]
+    fn enumeration_unrolls_cycles_up_to_the_bound() {
+        let (egraph, root) = cyclic_class();
+        let neg = |t: &str| format!("(neg {} {})", SIG1, t);
+        let terms = egraph.enumerate_terms(root, TermBound::Size(9), None);
+        assert_eq!(strings(&terms), ["x".to_string(), neg("x"), neg(&neg("x"))]);
+    }
+
+    #[test]
+    fn enumeration_stops_at_max_terms() {
+        let (egraph, root) = cyclic_class();
+        let all = egraph.enumerate_terms(root, TermBound::Size(17), None);
+        assert_eq!(all.len(), 5);
+        let capped = egraph.enumerate_terms(root, TermBound::Size(17), Some(2));
+        assert_eq!(strings(&capped), strings(&all[..2]));
+    }
+
+    #[test]
+    fn enumeration_is_in_nondecreasing_cost() {
+        let exprs = [
+            format!("(+ {} x (neg {} y))", SIG2, SIG1),
+            format!("(- {} x y)", SIG2),
+            format!("(* {} (sqrt {} x) (neg {} y))", SIG2, SIG1, SIG1),
+            "z".to_string(),
+        ];
+        let (egraph, root) = union_all(&exprs);
+        for bound in [TermBound::Size(15), TermBound::Depth(4)] {
+            let terms = egraph.enumerate_terms(root, bound, None);
+            assert_eq!(terms.len(), exprs.len(), "{:?}", bound);
+            for pair in terms.windows(2) {
+                assert!(pair[0].0 <= pair[1].0, "{:?}: {:?}", bound, strings(pair));
+            }
+        }
+    }
+}
@@ -1,13 +1,24 @@
 #![allow(clippy::missing_safety_doc)]
 
+pub mod extract;
 pub mod math;
+pub mod poly;
+pub mod report;
+pub mod rules;
+pub mod sexp;
+pub mod trace;
 
-use egg::{Extractor, Id, Language, StopReason, Symbol};
-use indexmap::IndexMap;
+use egg::{Id, Language, StopReason, Symbol};
+use extract::*;
+use indexmap::IndexSet;
 use libc::c_void;
 use math::*;
+use report::*;
+use trace::{trace_call, trace_result};
 
+use std::cell::OnceCell;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::mem::{self, ManuallyDrop};
 use std::os::raw::c_char;
@@ -18,23 +29,351 @@ use std::{slice, sync::atomic::Ordering};
 pub struct Context {
     iteration: usize,
     runner: Runner,
-    rules: Vec<Rewrite>,
+    // limits of each phase run so far
+    limits: Vec<Limits>,
+    iteration_callback: Option<IterationCallback>,
+    // extract the roots after every this many iterations, never if 0
+    extract_every: usize,
+    // fold constants only when floating-point evaluation gives the same value,
+    // handed to the analysis before nodes are added or rules run
+    fp_exact: bool,
+    // extraction from the final egraph, for iterations that were not extracted
+    final_extraction: OnceCell<IterData>,
+    // print extracted terms with `let` bindings for shared subterms
+    let_output: bool,
+    // direction names of the bidirectional rules given to runs, by base name
+    directions: HashMap<Symbol, [Symbol; 2]>,
+}
+
+// rules and limits of one phase of a run, see `Context::run_phases`
+pub struct Phase<'a> {
+    pub rules: &'a [Rewrite],
+    pub iter_limit: usize,
+    pub node_limit: usize,
+}
+
+// Called with each finished iteration and the `data` it was registered with,
+// returns true to stop the run. The iteration is only valid during the call.
+pub type IterationCallbackFn = unsafe extern "C" fn(*const EGraphIter, *mut c_void) -> bool;
+
+// stop message of a run halted by its iteration callback
+pub const CALLBACK_STOP: &str = "Stopped by iteration callback";
+
+#[derive(Clone, Copy)]
+struct IterationCallback {
+    callback: IterationCallbackFn,
+    data: *mut c_void,
+}
+
+impl IterationCallback {
+    // reports each iteration in turn, returns whether the callback asked to stop
+    fn report(&self, iterations: &[Iteration]) -> bool {
+        let mut stop = false;
+        for iteration in iterations {
+            let iteration = EGraphIter::new(iteration);
+            // Safety: the caller of `egraph_set_iteration_callback` vouches for `callback` and `data`
+            stop |= unsafe { (self.callback)(&iteration, self.data) };
+        }
+        stop
+    }
+}
+
+// Extracts the roots into the last iteration if every `every`th iteration is due one
+// and it has none yet. The egraph must not have changed since that iteration finished.
+fn extract_if_due(runner: &mut Runner, every: usize) {
+    let Some(last) = runner.iterations.len().checked_sub(1) else {
+        return;
+    };
+    if every == 0 || last % every != 0 || !runner.iterations[last].data.extracted.is_empty() {
+        return;
+    }
+
+    let extracted = IterData::extract(runner).extracted;
+    runner.iterations[last].data.extracted = extracted;
+}
+
+// Runs `rules` one iteration at a time until `iter_limit` iterations, reporting each
+// to `callback` as soon as it finishes. egg's hooks run before an iteration,
+// so a hook would see each iteration late and never see the last one.
+fn run_reporting(
+    mut runner: Runner,
+    rules: &[Rewrite],
+    iter_limit: usize,
+    extract_every: usize,
+    callback: IterationCallback,
+) -> Runner {
+    loop {
+        let reported = runner.iterations.len();
+        runner.stop_reason = None;
+        runner = runner
+            .with_iter_limit(min(reported.saturating_add(1), iter_limit))
+            .run(rules);
+        extract_if_due(&mut runner, extract_every);
+
+        if callback.report(&runner.iterations[reported..]) {
+            runner.stop_reason = Some(StopReason::Other(CALLBACK_STOP.into()));
+            return runner;
+        }
+        match runner.stop_reason {
+            Some(StopReason::IterationLimit(_)) if runner.iterations.len() < iter_limit => (),
+            _ => return runner,
+        }
+    }
 }
 
 const PROOF_BANDAID_STACK_SIZE: usize = 128 * 2usize.pow(20); // 128 MiB
 
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self {
+            iteration: 0,
+            runner: Runner::new(Default::default()).with_explanations_enabled(),
+            limits: vec![],
+            iteration_callback: None,
+            extract_every: 1,
+            fp_exact: false,
+            final_extraction: OnceCell::new(),
+            let_output: false,
+            directions: HashMap::new(),
+        }
+    }
+
+    pub fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    pub fn limits(&self) -> &[Limits] {
+        &self.limits
+    }
+
+    pub fn add_expr(&mut self, expr: &RecExpr) -> Id {
+        assert_eq!(self.iteration, 0);
+
+        self.runner.egraph.analysis.fp_exact = self.fp_exact;
+        let id = self.runner.egraph.add_expr(expr);
+        self.runner.roots.push(id);
+        self.final_extraction.take();
+        id
+    }
+
+    // folds constants only when floating-point evaluation would give the same value,
+    // must be set before adding expressions since folding happens as nodes are added,
+    // so returns false and changes nothing once the egraph is not empty
+    pub fn set_fp_exact_folding(&mut self, enabled: bool) -> bool {
+        if self.runner.egraph.number_of_classes() != 0 {
+            return false;
+        }
+
+        self.fp_exact = enabled;
+        true
+    }
+
+    // like `set_fp_exact_folding`, must be set before adding expressions
+    pub fn set_prune_policy(&mut self, policy: PrunePolicy) -> bool {
+        if self.runner.egraph.number_of_classes() != 0 {
+            return false;
+        }
+
+        self.runner.egraph.analysis.prune = policy;
+        true
+    }
+
+    // extract the roots after every `every` iterations, or never if 0,
+    // other iterations are extracted from the final egraph when asked for
+    pub fn set_extraction_interval(&mut self, every: usize) {
+        self.extract_every = every;
+    }
+
+    // prints extracted terms with shared subterms bound by `let`, see `sexp::to_let_sexp`
+    pub fn set_let_output(&mut self, enabled: bool) {
+        self.let_output = enabled;
+    }
+
+    // an extracted term in the configured output form
+    pub fn format_expr(&self, expr: &RecExpr) -> String {
+        if self.let_output {
+            sexp::to_let_sexp(expr)
+        } else {
+            expr.to_string()
+        }
+    }
+
+    // `callback` is called after every iteration of later runs, `None` removes it
+    pub fn set_iteration_callback(
+        &mut self,
+        callback: Option<IterationCallbackFn>,
+        data: *mut c_void,
+    ) {
+        self.iteration_callback = callback.map(|callback| IterationCallback { callback, data });
+    }
+
+    // adds both expressions and asserts that they are equal,
+    // recording `reason` as the justification used in proofs,
+    // their class becomes a root like those of `add_expr`
+    pub fn union_exprs(&mut self, a: &RecExpr, b: &RecExpr, reason: &str) -> Id {
+        assert_eq!(self.iteration, 0);
+
+        // union the terms as written, not their canonical classes,
+        // so proofs can explain them by `reason`
+        let egraph = &mut self.runner.egraph;
+        egraph.analysis.fp_exact = self.fp_exact;
+        let a_id = egraph.add_expr_uncanonical(a);
+        let b_id = egraph.add_expr_uncanonical(b);
+        egraph.union_trusted(a_id, b_id, reason);
+        egraph.rebuild();
+        let id = egraph.find(a_id);
+        self.runner.roots.push(id);
+        self.final_extraction.take();
+        id
+    }
+
+    // remembers the bidirectional rules of `ruleset`, see `times_applied`
+    pub fn add_directions(&mut self, ruleset: &Ruleset) {
+        self.directions.extend(ruleset.directions.clone());
+    }
+
+    // Counts applications of the rule `name`. Given the base name of a
+    // bidirectional rule this is the combined count of both directions.
+    pub fn times_applied(&self, name: &str) -> usize {
+        let name = Symbol::from(name);
+        let names = match self.directions.get(&name) {
+            Some(directions) => directions.to_vec(),
+            None => vec![name],
+        };
+
+        self.runner
+            .iterations
+            .iter()
+            .flat_map(|iter| names.iter().map(|n| *iter.applied.get(n).unwrap_or(&0)))
+            .sum()
+    }
+
+    // runs the rules to saturation or until a limit is hit,
+    // does nothing if the egraph has already been run
+    pub fn run(
+        &mut self,
+        rules: &[Rewrite],
+        iter_limit: usize,
+        node_limit: usize,
+        is_constant_folding_enabled: bool,
+    ) {
+        let phase = Phase {
+            rules,
+            iter_limit,
+            node_limit,
+        };
+        self.run_phases(&[phase], is_constant_folding_enabled);
+    }
+
+    // Runs each phase in turn on the same egraph, until it saturates or hits its own limits.
+    // Unsoundness or a stop requested by the iteration callback ends the whole run.
+    // Does nothing if the egraph has already been run.
+    pub fn run_phases(&mut self, phases: &[Phase], is_constant_folding_enabled: bool) {
+        if self.runner.stop_reason.is_some() {
+            return;
+        }
+
+        let mut runner = mem::replace(&mut self.runner, Runner::new(Default::default()));
+        runner.egraph.analysis.constant_fold = is_constant_folding_enabled;
+        runner.egraph.analysis.fp_exact = self.fp_exact;
+        runner = runner.with_time_limit(Duration::from_secs(u64::MAX));
+
+        // Hooks run before each iteration, while the egraph is still as the
+        // previous one left it. This comes first so that the last sound
+        // iteration is extracted before unsoundness stops the run.
+        let extract_every = self.extract_every;
+        runner = runner.with_hook(move |r| {
+            extract_if_due(r, extract_every);
+            Ok(())
+        });
+        runner = runner.with_hook(|r| {
+            if r.egraph.analysis.unsound.load(Ordering::SeqCst) {
+                Err("Unsoundness detected".into())
+            } else {
+                Ok(())
+            }
+        });
+
+        for (i, phase) in phases.iter().enumerate() {
+            // limits are per phase, so count iterations from where this phase starts
+            let iter_limit = runner.iterations.len().saturating_add(phase.iter_limit);
+            runner.egraph.analysis.phase = i;
+            runner.stop_reason = None;
+            runner = runner.with_node_limit(phase.node_limit);
+            // the iteration limit should never hit
+            runner = match self.iteration_callback {
+                None => runner.with_iter_limit(iter_limit).run(phase.rules),
+                Some(callback) => {
+                    run_reporting(runner, phase.rules, iter_limit, extract_every, callback)
+                }
+            };
+            // no hook runs after the last iteration
+            extract_if_due(&mut runner, extract_every);
+
+            self.limits.push(Limits {
+                phase: i,
+                iter_limit: phase.iter_limit,
+                node_limit: phase.node_limit,
+            });
+            if let Some(StopReason::Other(_)) = runner.stop_reason {
+                break;
+            }
+        }
+
+        self.runner = runner;
+        self.final_extraction.take();
+    }
+
+    // `None` if `id` is not in the class of a root, or if the run
+    // was unsound and no iteration before that was extracted
+    pub fn get_simplest(&self, id: Id, iter: usize) -> Option<&Extracted> {
+        find_extracted(&self.runner, &self.final_extraction, id, iter)
+    }
+
+    pub fn get_proof(&mut self, expr: &RecExpr, goal: &RecExpr) -> String {
+        // explaining adds both terms to the egraph
+        self.final_extraction.take();
+        // Send `EGraph` since neither `Context` nor `Runner` are `Send`. `Runner::explain_equivalence` just forwards to `EGraph::explain_equivalence` so this is fine.
+        let egraph = &mut self.runner.egraph;
+        let thread = thread::Builder::new().stack_size(PROOF_BANDAID_STACK_SIZE);
+
+        // *Java programmers hate him! Prevent stack overflows with this one weird trick!*
+        thread::scope(|scope| {
+            thread
+                .spawn_scoped(scope, move || {
+                    egraph
+                        .explain_equivalence(expr, goal)
+                        .get_string_with_let()
+                        .replace('\n', "")
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        })
+    }
+}
+
 // I had to add $(rustc --print sysroot)/lib to LD_LIBRARY_PATH to get linking to work after installing rust with rustup
 #[no_mangle]
 pub unsafe extern "C" fn egraph_create() -> *mut Context {
-    Box::into_raw(Box::new(Context {
-        iteration: 0,
-        runner: Runner::new(Default::default()).with_explanations_enabled(),
-        rules: vec![],
-    }))
+    trace_call!("egraph_create", []);
+    let ptr = Box::into_raw(Box::new(Context::new()));
+    trace::register_context(ptr);
+
+    ptr
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn egraph_destroy(ptr: *mut Context) {
+    trace_call!("egraph_destroy", [trace::context(ptr)]);
+    trace::forget_context(ptr);
+
     drop(Box::from_raw(ptr))
 }
 
@@ -55,31 +394,241 @@ pub struct EGraphIter {
     numnodes: u32,
     numclasses: u32,
     time: f64,
+    search_time: f64,
+    apply_time: f64,
+    rebuild_time: f64,
+    numrebuilds: u32,
+    stop_reason: u32,
+    applied_length: u32,
+    applied: *mut FFIRuleCount,
+    costs_length: u32,
+    costs: *mut u32,
+    phase: u32,
 }
 
-// a struct for loading rules from external source
+// number of times a rule was applied in a single iteration
+#[repr(C)]
+pub struct FFIRuleCount {
+    name: *mut c_char,
+    count: u32,
+}
+
+impl EGraphIter {
+    fn new(iteration: &Iteration) -> Self {
+        let applied = iteration
+            .applied
+            .iter()
+            .map(|(name, count)| FFIRuleCount {
+                name: CString::new(name.to_string()).unwrap().into_raw(),
+                count: *count as u32,
+            })
+            .collect::<Vec<_>>();
+        let costs = iteration
+            .data
+            .extracted
+            .iter()
+            .map(|(_, ext)| ext.cost as u32)
+            .collect::<Vec<_>>();
+
+        EGraphIter {
+            numnodes: iteration.egraph_nodes as u32,
+            numclasses: iteration.egraph_classes as u32,
+            time: iteration.total_time,
+            search_time: iteration.search_time,
+            apply_time: iteration.apply_time,
+            rebuild_time: iteration.rebuild_time,
+            numrebuilds: iteration.n_rebuilds as u32,
+            stop_reason: stop_reason_code(&iteration.stop_reason),
+            applied_length: applied.len() as u32,
+            applied: box_slice_into_raw(applied),
+            costs_length: costs.len() as u32,
+            costs: box_slice_into_raw(costs),
+            phase: iteration.data.phase as u32,
+        }
+    }
+}
+
+impl Drop for EGraphIter {
+    fn drop(&mut self) {
+        // Safety: both arrays were allocated by `box_slice_into_raw` in `EGraphIter::new`
+        unsafe {
+            let applied = box_slice_from_raw(self.applied, self.applied_length as usize);
+            for rule_count in applied.iter() {
+                drop(CString::from_raw(rule_count.name));
+            }
+            drop(box_slice_from_raw(self.costs, self.costs_length as usize));
+        }
+    }
+}
+
+fn box_slice_into_raw<T>(v: Vec<T>) -> *mut T {
+    Box::into_raw(v.into_boxed_slice()) as *mut T
+}
+
+unsafe fn box_slice_from_raw<T>(ptr: *mut T, len: usize) -> Box<[T]> {
+    Box::from_raw(slice::from_raw_parts_mut(ptr, len))
+}
+
+// a struct for loading rules from external source,
+// a bidirectional rule compiles into one rewrite per direction
 #[repr(C)]
 pub struct FFIRule {
     name: *const c_char,
     left: *const c_char,
     right: *const c_char,
+    bidirectional: bool,
+}
+
+impl FFIRule {
+    pub fn new(
+        name: *const c_char,
+        left: *const c_char,
+        right: *const c_char,
+        bidirectional: bool,
+    ) -> Self {
+        Self {
+            name,
+            left,
+            right,
+            bidirectional,
+        }
+    }
+}
+
+// rules compiled by `egraph_register_rules`
+pub struct Ruleset {
+    pub rewrites: Vec<Rewrite>,
+    // direction names of each bidirectional rule, by base name
+    directions: HashMap<Symbol, [Symbol; 2]>,
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn egraph_add_expr(ptr: *mut Context, expr: *const c_char) -> u32 {
     let _ = env_logger::try_init();
     // Safety: `ptr` was box allocated by `egraph_create`
-    let mut context = Box::from_raw(ptr);
-
-    assert_eq!(context.iteration, 0);
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
 
+    trace_call!("egraph_add_expr", [trace::context(ptr), trace::c_str(expr)]);
     let rec_expr = CStr::from_ptr(expr).to_str().unwrap().parse().unwrap();
-    context.runner = context.runner.with_expr(&rec_expr);
-    let id = usize::from(*context.runner.roots.last().unwrap())
+    let id: u32 = usize::from(context.add_expr(&rec_expr)).try_into().unwrap();
+    trace_result!(id);
+
+    id
+}
+
+// Must be called before any expression is added, returns false and does nothing otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_set_fp_exact_folding(ptr: *mut Context, enabled: bool) -> bool {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_set_fp_exact_folding",
+        [trace::context(ptr), enabled]
+    );
+    let is_set = context.set_fp_exact_folding(enabled);
+    trace_result!(is_set);
+
+    is_set
+}
+
+// Sets which enodes constant classes keep: 0 off, 1 only the constant leaf,
+// 2 the constant leaf and the `k` cheapest other enodes.
+// Must be called before any expression is added.
+// Returns false and does nothing for an unknown policy or a non-empty egraph.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_set_prune_policy(ptr: *mut Context, policy: u32, k: u32) -> bool {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!("egraph_set_prune_policy", [trace::context(ptr), policy, k]);
+    let is_set = match PrunePolicy::from_code(policy, k) {
+        Some(policy) => context.set_prune_policy(policy),
+        None => false,
+    };
+    trace_result!(is_set);
+
+    is_set
+}
+
+// Extracts the roots after every `every` iterations, or never if 0 (default 1).
+// `egraph_get_simplest` and `egraph_get_cost` extract from the final egraph
+// when asked about an iteration that was not extracted, unless it is unsound.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_set_extraction_interval(ptr: *mut Context, every: u32) {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_set_extraction_interval",
+        [trace::context(ptr), every]
+    );
+    context.set_extraction_interval(every as usize);
+}
+
+// Prints the terms returned by `egraph_get_simplest`, `egraph_get_simplest_allowed`,
+// `egraph_get_variants` and `egraph_find_variants` with `(let (<var> <term>) <body>)`
+// bindings for subterms used more than once, as read by `flatten-let` in Herbie.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_set_let_output(ptr: *mut Context, enabled: bool) {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!("egraph_set_let_output", [trace::context(ptr), enabled]);
+    context.set_let_output(enabled);
+}
+
+// Registers `callback` to be called with `data` after each iteration of every
+// later run (`egraph_run`, `egraph_run_rules`, ...), with the iteration's statistics
+// and the best cost of each root (none if the iteration was not extracted,
+// see `egraph_set_extraction_interval`). Each iteration is reported as soon as it
+// finishes, and returning true halts the run there with stop reason 5.
+// A null `callback` removes it. `callback` and `data` must stay valid until then.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_set_iteration_callback(
+    ptr: *mut Context,
+    callback: Option<IterationCallbackFn>,
+    data: *mut c_void,
+) {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_set_iteration_callback",
+        [trace::context(ptr), callback.is_some()]
+    );
+    context.set_iteration_callback(callback, data);
+}
+
+// Asserts `a` and `b` are equal because of `reason`, which names the step in proofs.
+// Returns the eclass both expressions now belong to, which is extracted like
+// the eclass of an expression given to `egraph_add_expr`.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_union_exprs(
+    ptr: *mut Context,
+    a: *const c_char,
+    b: *const c_char,
+    reason: *const c_char,
+) -> u32 {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_union_exprs",
+        [
+            trace::context(ptr),
+            trace::c_str(a),
+            trace::c_str(b),
+            trace::c_str(reason)
+        ]
+    );
+    let a_rec = CStr::from_ptr(a).to_str().unwrap().parse().unwrap();
+    let b_rec = CStr::from_ptr(b).to_str().unwrap().parse().unwrap();
+    let reason = CStr::from_ptr(reason).to_str().unwrap();
+    let id: u32 = usize::from(context.union_exprs(&a_rec, &b_rec, reason))
         .try_into()
         .unwrap();
-
-    mem::forget(context);
+    trace_result!(id);
 
     id
 }
@@ -99,11 +648,110 @@ unsafe fn ffirule_to_tuple(rule_ptr: *mut FFIRule) -> (String, String, String) {
     )
 }
 
+// Compiles the rules, or reports the first that does not compile.
+// Only a rule that can be read both ways can be bidirectional,
+// so its right side cannot name an applier or bind new variables.
+unsafe fn ffirules_to_ruleset(
+    rules_array_ptr: *const *mut FFIRule,
+    rules_array_length: u32,
+) -> Result<Ruleset, String> {
+    let length: usize = rules_array_length as usize;
+    let ffi_rules: &[*mut FFIRule] = slice::from_raw_parts(rules_array_ptr, length);
+    let mut ffi_tuples: Vec<(&str, &str, &str)> = vec![];
+    let mut ffi_strings: Vec<(String, String, String)> = vec![];
+    let mut directions = HashMap::new();
+    for ffi_rule in ffi_rules.iter() {
+        let (name, left, right) = ffirule_to_tuple(*ffi_rule);
+        if (**ffi_rule).bidirectional {
+            if right.trim().starts_with("#:applier") {
+                return Err(format!(
+                    "rule {}: a rule with a named applier cannot be bidirectional",
+                    name
+                ));
+            }
+            let [forward, backward] = direction_names(&name);
+            directions.insert(
+                Symbol::from(&name),
+                [Symbol::from(&forward), Symbol::from(&backward)],
+            );
+            ffi_strings.push((forward, left.clone(), right.clone()));
+            ffi_strings.push((backward, right, left));
+        } else {
+            ffi_strings.push((name, left, right));
+        }
+    }
+
+    for ffi_string in ffi_strings.iter() {
+        ffi_tuples.push((&ffi_string.0, &ffi_string.1, &ffi_string.2));
+    }
+
+    // a backward rule using variables its left side does not bind fails here
+    Ok(Ruleset {
+        rewrites: math::mk_rules(&ffi_tuples)?,
+        directions,
+    })
+}
+
+unsafe fn write_iterations(
+    runner: &Runner,
+    iterations_length: *mut u32,
+    iterations_ptr: *mut *mut c_void,
+) -> *const EGraphIter {
+    let iterations = runner
+        .iterations
+        .iter()
+        .map(EGraphIter::new)
+        .collect::<Vec<_>>();
+    let iterations_data = iterations.as_ptr();
+
+    trace_result!(trace::iterations(&iterations));
+    std::ptr::write(iterations_length, iterations.len() as u32);
+    std::ptr::write(
+        iterations_ptr,
+        Box::into_raw(Box::new(iterations)) as *mut c_void,
+    );
+
+    iterations_data
+}
+
+// Compiles rules once so that they can be shared across runs.
+// Must be freed with `destroy_rules`. Returns null if a rule does not compile.
 #[no_mangle]
-pub unsafe extern "C" fn egraph_run_with_iter_limit(
-    ptr: *mut Context,
+pub unsafe extern "C" fn egraph_register_rules(
     rules_array_ptr: *const *mut FFIRule,
     rules_array_length: u32,
+) -> *mut Ruleset {
+    trace_call!(
+        "egraph_register_rules",
+        [trace::ffi_rules(rules_array_ptr, rules_array_length)]
+    );
+    let ruleset = match ffirules_to_ruleset(rules_array_ptr, rules_array_length) {
+        Ok(ruleset) => ruleset,
+        Err(e) => {
+            log::warn!("Could not compile rules: {}", e);
+            trace_result!(trace::Field::Null);
+            return std::ptr::null_mut();
+        }
+    };
+    let ptr = Box::into_raw(Box::new(ruleset));
+    trace::register_ruleset(ptr);
+    trace_result!(trace::ruleset(ptr));
+
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn destroy_rules(ptr: *mut Ruleset) {
+    trace_call!("destroy_rules", [trace::ruleset(ptr)]);
+    trace::forget_ruleset(ptr);
+
+    drop(Box::from_raw(ptr))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn egraph_run_rules(
+    ptr: *mut Context,
+    rules_ptr: *const Ruleset,
     iterations_length: *mut u32,
     iterations_ptr: *mut *mut c_void,
     iter_limit: u32,
@@ -111,61 +759,123 @@ pub unsafe extern "C" fn egraph_run_with_iter_limit(
     is_constant_folding_enabled: bool,
 ) -> *const EGraphIter {
     // Safety: `ptr` was box allocated by `egraph_create`
-    let mut context = Box::from_raw(ptr);
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+    // Safety: `rules_ptr` was box allocated by `egraph_register_rules`
+    let ruleset = &*rules_ptr;
 
-    if context.runner.stop_reason.is_none() {
-        let length: usize = rules_array_length as usize;
-        let ffi_rules: &[*mut FFIRule] = slice::from_raw_parts(rules_array_ptr, length);
-        let mut ffi_tuples: Vec<(&str, &str, &str)> = vec![];
-        let mut ffi_strings: Vec<(String, String, String)> = vec![];
-        for ffi_rule in ffi_rules.iter() {
-            let str_tuple = ffirule_to_tuple(*ffi_rule);
-            ffi_strings.push(str_tuple);
-        }
+    trace_call!(
+        "egraph_run_rules",
+        [
+            trace::context(ptr),
+            trace::ruleset(rules_ptr),
+            iter_limit,
+            node_limit,
+            is_constant_folding_enabled
+        ]
+    );
+    context.add_directions(ruleset);
+    context.run(
+        &ruleset.rewrites,
+        iter_limit as usize,
+        node_limit as usize,
+        is_constant_folding_enabled,
+    );
 
-        for ffi_string in ffi_strings.iter() {
-            ffi_tuples.push((&ffi_string.0, &ffi_string.1, &ffi_string.2));
-        }
+    write_iterations(&context.runner, iterations_length, iterations_ptr)
+}
 
-        let rules: Vec<Rewrite> = math::mk_rules(&ffi_tuples);
-        context.rules = rules;
-
-        context.runner.egraph.analysis.constant_fold = is_constant_folding_enabled;
-        context.runner = context
-            .runner
-            .with_node_limit(node_limit as usize)
-            .with_iter_limit(iter_limit as usize) // should never hit
-            .with_time_limit(Duration::from_secs(u64::MAX))
-            .with_hook(|r| {
-                if r.egraph.analysis.unsound.load(Ordering::SeqCst) {
-                    Err("Unsoundness detected".into())
-                } else {
-                    Ok(())
-                }
-            })
-            .run(&context.rules);
-    }
+// Runs phase `i` with ruleset `rules[i]` (from `egraph_register_rules`),
+// `iter_limits[i]` and `node_limits[i]`, one after the other on the same egraph.
+// Each returned iteration records the index of its phase.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_run_phases(
+    ptr: *mut Context,
+    rules_array_ptr: *const *const Ruleset,
+    iter_limits_ptr: *const u32,
+    node_limits_ptr: *const u32,
+    phases_length: u32,
+    iterations_length: *mut u32,
+    iterations_ptr: *mut *mut c_void,
+    is_constant_folding_enabled: bool,
+) -> *const EGraphIter {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
 
-    let iterations = context
-        .runner
-        .iterations
+    let len = phases_length as usize;
+    let rules = slice::from_raw_parts(rules_array_ptr, len);
+    let iter_limits = slice::from_raw_parts(iter_limits_ptr, len);
+    let node_limits = slice::from_raw_parts(node_limits_ptr, len);
+
+    trace_call!(
+        "egraph_run_phases",
+        [
+            trace::context(ptr),
+            trace::rulesets(rules),
+            trace::numbers(iter_limits),
+            trace::numbers(node_limits),
+            is_constant_folding_enabled
+        ]
+    );
+    // Safety: each ruleset was box allocated by `egraph_register_rules`
+    let rulesets: Vec<&Ruleset> = rules.iter().map(|&rules_ptr| &*rules_ptr).collect();
+    for ruleset in &rulesets {
+        context.add_directions(ruleset);
+    }
+    let phases: Vec<Phase> = rulesets
         .iter()
-        .map(|iteration| EGraphIter {
-            numnodes: iteration.egraph_nodes as u32,
-            numclasses: iteration.egraph_classes as u32,
-            time: iteration.total_time,
+        .zip(iter_limits.iter().zip(node_limits))
+        .map(|(ruleset, (&iter_limit, &node_limit))| Phase {
+            rules: &ruleset.rewrites,
+            iter_limit: iter_limit as usize,
+            node_limit: node_limit as usize,
         })
-        .collect::<Vec<_>>();
-    let iterations_data = iterations.as_ptr();
+        .collect();
+    context.run_phases(&phases, is_constant_folding_enabled);
 
-    std::ptr::write(iterations_length, iterations.len() as u32);
-    std::ptr::write(
-        iterations_ptr,
-        Box::into_raw(Box::new(iterations)) as *mut c_void,
+    write_iterations(&context.runner, iterations_length, iterations_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn egraph_run_with_iter_limit(
+    ptr: *mut Context,
+    rules_array_ptr: *const *mut FFIRule,
+    rules_array_length: u32,
+    iterations_length: *mut u32,
+    iterations_ptr: *mut *mut c_void,
+    iter_limit: u32,
+    node_limit: u32,
+    is_constant_folding_enabled: bool,
+) -> *const EGraphIter {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let mut context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_run_with_iter_limit",
+        [
+            trace::context(ptr),
+            trace::ffi_rules(rules_array_ptr, rules_array_length),
+            iter_limit,
+            node_limit,
+            is_constant_folding_enabled
+        ]
     );
-    mem::forget(context);
+    if context.runner.stop_reason.is_none() {
+        // rules that do not compile leave the egraph as it was
+        match ffirules_to_ruleset(rules_array_ptr, rules_array_length) {
+            Ok(ruleset) => {
+                context.add_directions(&ruleset);
+                context.run(
+                    &ruleset.rewrites,
+                    iter_limit as usize,
+                    node_limit as usize,
+                    is_constant_folding_enabled,
+                );
+            }
+            Err(e) => log::warn!("Could not compile rules: {}", e),
+        }
+    }
 
-    iterations_data
+    write_iterations(&context.runner, iterations_length, iterations_ptr)
 }
 
 #[no_mangle]
@@ -195,17 +905,36 @@ pub unsafe extern "C" fn egraph_get_stop_reason(ptr: *mut Context) -> u32 {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
 
-    match context.runner.stop_reason {
+    trace_call!("egraph_get_stop_reason", [trace::context(ptr)]);
+    let code = stop_reason_code(&context.runner.stop_reason);
+    trace_result!(code);
+
+    code
+}
+
+fn stop_reason_code(stop_reason: &Option<StopReason>) -> u32 {
+    match stop_reason {
         Some(StopReason::Saturated) => 0,
         Some(StopReason::IterationLimit(_)) => 1,
         Some(StopReason::NodeLimit(_)) => 2,
+        Some(StopReason::Other(msg)) if msg == CALLBACK_STOP => 5,
         Some(StopReason::Other(_)) => 3,
         _ => 4,
     }
 }
 
-fn find_extracted(runner: &Runner, id: u32, iter: u32) -> &Extracted {
-    let id = runner.egraph.find(Id::from(id as usize));
+// Extraction of `id` at `iter`, falling back to extracting from the final egraph
+// (once, into `fallback`) if that iteration was not extracted. Only roots are
+// extracted, so this is `None` if `id` is not in the class of one. An unsound
+// final egraph is never extracted either, so this is also `None` if the run was
+// unsound and no earlier iteration was extracted.
+pub(crate) fn find_extracted<'a>(
+    runner: &'a Runner,
+    fallback: &'a OnceCell<IterData>,
+    id: Id,
+    iter: usize,
+) -> Option<&'a Extracted> {
+    let id = runner.egraph.find(id);
 
     // go back one more iter, egg can duplicate the final iter in the case of an error
     let is_unsound = runner.egraph.analysis.unsound.load(Ordering::SeqCst);
@@ -214,18 +943,35 @@ fn find_extracted(runner: &Runner, id: u32, iter: u32) -> &Extracted {
             .iterations
             .len()
             .saturating_sub(if is_unsound { 3 } else { 1 }),
-        iter as usize,
+        iter,
     );
 
-    runner.iterations[sound_iter]
-        .data
-        .extracted
+    let is_extracted = |iteration: &&Iteration| !iteration.data.extracted.is_empty();
+    let iteration = if is_unsound {
+        // the final egraph is unsound, so use the last extraction from before
+        runner
+            .iterations
+            .iter()
+            .take(sound_iter + 1)
+            .rev()
+            .find(is_extracted)
+    } else {
+        runner.iterations.get(sound_iter).filter(is_extracted)
+    };
+    let data = match iteration {
+        Some(iteration) => &iteration.data,
+        None if is_unsound => return None,
+        None => fallback.get_or_init(|| IterData::extract(runner)),
+    };
+
+    data.extracted
         .iter()
         .find(|(i, _)| runner.egraph.find(*i) == id)
         .map(|(_, ext)| ext)
-        .expect("Couldn't find matching extraction!")
 }
 
+// Returns null if `node_id` is not the class of an expression given to `egraph_add_expr`
+// or `egraph_union_exprs`, or if the run was unsound and no iteration before that was extracted.
 #[no_mangle]
 pub unsafe extern "C" fn egraph_get_simplest(
     ptr: *mut Context,
@@ -234,10 +980,68 @@ pub unsafe extern "C" fn egraph_get_simplest(
 ) -> *const c_char {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
-    let ext = find_extracted(&context.runner, node_id, iter);
-    let best_str = ManuallyDrop::new(CString::new(ext.best.to_string()).unwrap());
+    trace_call!("egraph_get_simplest", [trace::context(ptr), node_id, iter]);
+    let best = context
+        .get_simplest(Id::from(node_id as usize), iter as usize)
+        .map(|ext| context.format_expr(&ext.best));
+    trace_result!(best.as_deref());
+    match best {
+        Some(best) => {
+            let best_str = ManuallyDrop::new(CString::new(best).unwrap());
+            best_str.as_ptr()
+        }
+        None => std::ptr::null(),
+    }
+}
 
-    best_str.as_ptr()
+// Like `egraph_get_simplest` but never picks a denied operator or type
+// and always extracts from the final egraph. Returns null and leaves
+// `cost` untouched when there is no extractable term, or when the run
+// was unsound since the final egraph is then never extracted.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_simplest_allowed(
+    ptr: *mut Context,
+    node_id: u32,
+    denied_ops_ptr: *const *const c_char,
+    denied_ops_length: u32,
+    denied_types_ptr: *const *const c_char,
+    denied_types_length: u32,
+    cost: *mut u32,
+) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+    trace_call!(
+        "egraph_get_simplest_allowed",
+        [
+            trace::context(ptr),
+            node_id,
+            trace::c_strs(denied_ops_ptr, denied_ops_length),
+            trace::c_strs(denied_types_ptr, denied_types_length)
+        ]
+    );
+    let deny = DenyList {
+        ops: ptr_to_strings(denied_ops_ptr, denied_ops_length),
+        types: ptr_to_strings(denied_types_ptr, denied_types_length),
+    };
+
+    let id = Id::from(node_id as usize);
+    let egraph = &context.runner.egraph;
+    let is_unsound = egraph.analysis.unsound.load(Ordering::SeqCst);
+    let best = (!is_unsound)
+        .then(|| find_best_allowed(egraph, id, &deny))
+        .flatten()
+        .map(|(best_cost, best)| (best_cost, context.format_expr(&best)));
+    trace_result!(best
+        .as_ref()
+        .map(|(best_cost, best)| format!("{} {}", best_cost, best)));
+    match best {
+        Some((best_cost, best)) => {
+            std::ptr::write(cost, best_cost as u32);
+            let best_str = ManuallyDrop::new(CString::new(best).unwrap());
+            best_str.as_ptr()
+        }
+        None => std::ptr::null(),
+    }
 }
 
 #[no_mangle]
@@ -248,25 +1052,14 @@ pub unsafe extern "C" fn egraph_get_proof(
 ) -> *const c_char {
     // Safety: `ptr` was box allocated by `egraph_create`
     let mut context = ManuallyDrop::new(Box::from_raw(ptr));
-    // Send `EGraph` since neither `Context` nor `Runner` are `Send`. `Runner::explain_equivalence` just forwards to `EGraph::explain_equivalence` so this is fine.
-    let egraph = &mut context.runner.egraph;
+    trace_call!(
+        "egraph_get_proof",
+        [trace::context(ptr), trace::c_str(expr), trace::c_str(goal)]
+    );
     let expr_rec = CStr::from_ptr(expr).to_str().unwrap().parse().unwrap();
     let goal_rec = CStr::from_ptr(goal).to_str().unwrap().parse().unwrap();
-    let thread = thread::Builder::new().stack_size(PROOF_BANDAID_STACK_SIZE);
-
-    // *Java programmers hate him! Prevent stack overflows with this one weird trick!*
-    let string = thread::scope(|scope| {
-        thread
-            .spawn_scoped(scope, move || {
-                egraph
-                    .explain_equivalence(&expr_rec, &goal_rec)
-                    .get_string_with_let()
-                    .replace('\n', "")
-            })
-            .unwrap()
-            .join()
-            .unwrap()
-    });
+    let string = context.get_proof(&expr_rec, &goal_rec);
+    trace_result!(string.as_str());
     let c_string = ManuallyDrop::new(CString::new(string).unwrap());
 
     c_string.as_ptr()
@@ -283,11 +1076,19 @@ pub unsafe extern "C" fn egraph_is_equal(
 
     assert_eq!(context.iteration, 0);
 
+    trace_call!(
+        "egraph_is_equal",
+        [trace::context(ptr), trace::c_str(expr), trace::c_str(goal)]
+    );
     let expr_rec = CStr::from_ptr(expr).to_str().unwrap().parse().unwrap();
     let goal_rec = CStr::from_ptr(goal).to_str().unwrap().parse().unwrap();
     let egraph = &mut context.runner.egraph;
 
-    egraph.add_expr(&expr_rec) == egraph.add_expr(&goal_rec)
+    let is_equal = egraph.add_expr(&expr_rec) == egraph.add_expr(&goal_rec);
+    context.final_extraction.take();
+    trace_result!(is_equal);
+
+    is_equal
 }
 
 #[no_mangle]
@@ -299,74 +1100,449 @@ pub unsafe extern "C" fn egraph_get_variants(
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
 
+    trace_call!(
+        "egraph_get_variants",
+        [trace::context(ptr), node_id, trace::c_str(orig_expr)]
+    );
+
     // root (id, expr)
     let id = Id::from(node_id as usize);
     let orig_recexpr: RecExpr = CStr::from_ptr(orig_expr).to_str().unwrap().parse().unwrap();
-    let head_node = &orig_recexpr.as_ref()[orig_recexpr.as_ref().len() - 1];
-
-    // extractor
-    let extractor = Extractor::new(&context.runner.egraph, AltCost::new(&context.runner.egraph));
-    let mut cache: IndexMap<Id, RecExpr> = Default::default();
 
     // extract variants
-    let mut exprs = vec![];
-    for n in &context.runner.egraph[id].nodes {
-        // assuming same ops in an eclass cannot
-        // have different precisions
-        if !n.matches(head_node) {
-            // extract if not in cache
-            n.for_each(|id| {
-                if cache.get(&id).is_none() {
-                    let (_, best) = extractor.find_best(id);
-                    cache.insert(id, best);
-                }
-            });
-
-            exprs.push(n.join_recexprs(|id| cache.get(&id).unwrap().as_ref()));
-        }
-    }
+    let variants = node_variants(&context.runner.egraph, id, &orig_recexpr);
 
     // format
-    let expr_strs: Vec<String> = exprs.iter().map(|r| r.to_string()).collect();
+    let expr_strs: Vec<String> = variants
+        .iter()
+        .map(|v| context.format_expr(&v.expr))
+        .collect();
     let best_str = ManuallyDrop::new(CString::new(expr_strs.join(" ")).unwrap());
+    trace_result!(trace::c_str(best_str.as_ptr()));
 
     best_str.as_ptr()
 }
 
+// an extracted variant, see `egraph_find_variants`
+#[repr(C)]
+pub struct FFIVariant {
+    expr: *mut c_char,
+    op: *mut c_char,
+    cost: u32,
+}
+
+impl Drop for FFIVariant {
+    fn drop(&mut self) {
+        // Safety: both strings were allocated by `CString::into_raw` in `egraph_find_variants`
+        unsafe {
+            drop(CString::from_raw(self.expr));
+            drop(CString::from_raw(self.op));
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn destroy_variants(ptr: *mut c_void) {
+    drop(Box::from_raw(ptr as *mut Vec<FFIVariant>));
+}
+
+unsafe fn ptr_to_strings(array_ptr: *const *const c_char, array_length: u32) -> Vec<String> {
+    if array_length == 0 {
+        return vec![];
+    }
+
+    slice::from_raw_parts(array_ptr, array_length as usize)
+        .iter()
+        .map(|&ptr| ptr_to_string(ptr))
+        .collect()
+}
+
+// Like `egraph_get_variants` but returns each variant with its cost and
+// head operator, cheapest first and without duplicate terms, and only those
+// allowed by the operator and type filters.
+// A `max_variants` of 0 means no limit.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_find_variants(
+    ptr: *mut Context,
+    node_id: u32,
+    orig_expr: *const c_char,
+    include_ops_ptr: *const *const c_char,
+    include_ops_length: u32,
+    exclude_ops_ptr: *const *const c_char,
+    exclude_ops_length: u32,
+    include_types_ptr: *const *const c_char,
+    include_types_length: u32,
+    exclude_types_ptr: *const *const c_char,
+    exclude_types_length: u32,
+    max_variants: u32,
+    variants_length: *mut u32,
+    variants_ptr: *mut *mut c_void,
+) -> *const FFIVariant {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_find_variants",
+        [
+            trace::context(ptr),
+            node_id,
+            trace::c_str(orig_expr),
+            trace::c_strs(include_ops_ptr, include_ops_length),
+            trace::c_strs(exclude_ops_ptr, exclude_ops_length),
+            trace::c_strs(include_types_ptr, include_types_length),
+            trace::c_strs(exclude_types_ptr, exclude_types_length),
+            max_variants
+        ]
+    );
+    let id = Id::from(node_id as usize);
+    let orig_recexpr: RecExpr = CStr::from_ptr(orig_expr).to_str().unwrap().parse().unwrap();
+    let filter = VariantFilter {
+        include_ops: ptr_to_strings(include_ops_ptr, include_ops_length),
+        exclude_ops: ptr_to_strings(exclude_ops_ptr, exclude_ops_length),
+        include_types: ptr_to_strings(include_types_ptr, include_types_length),
+        exclude_types: ptr_to_strings(exclude_types_ptr, exclude_types_length),
+        max_variants: if max_variants == 0 {
+            None
+        } else {
+            Some(max_variants as usize)
+        },
+    };
+
+    let variants = find_variants(&context.runner.egraph, id, &orig_recexpr, &filter)
+        .into_iter()
+        .map(|v| FFIVariant {
+            expr: CString::new(context.format_expr(&v.expr))
+                .unwrap()
+                .into_raw(),
+            op: CString::new(v.op).unwrap().into_raw(),
+            cost: v.cost as u32,
+        })
+        .collect::<Vec<_>>();
+    let variants_data = variants.as_ptr();
+    trace_result!(trace::variants(&variants));
+
+    std::ptr::write(variants_length, variants.len() as u32);
+    std::ptr::write(
+        variants_ptr,
+        Box::into_raw(Box::new(variants)) as *mut c_void,
+    );
+
+    variants_data
+}
+
+// Draws `n` random terms of at most `max_size` nodes from the eclass of `node_id`,
+// space separated like `egraph_get_variants`. Deterministic for a given `seed`.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_sample_terms(
+    ptr: *mut Context,
+    node_id: u32,
+    n: u32,
+    seed: u64,
+    max_size: u32,
+    is_weighted_by_cost: bool,
+) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_sample_terms",
+        [
+            trace::context(ptr),
+            node_id,
+            n,
+            seed,
+            max_size,
+            is_weighted_by_cost
+        ]
+    );
+    let id = Id::from(node_id as usize);
+    let terms = sample_terms(
+        &context.runner.egraph,
+        id,
+        n as usize,
+        seed,
+        max_size as usize,
+        is_weighted_by_cost,
+    );
+
+    let term_strs: Vec<String> = terms.iter().map(|t| t.to_string()).collect();
+    let terms_str = ManuallyDrop::new(CString::new(term_strs.join(" ")).unwrap());
+    trace_result!(trace::c_str(terms_str.as_ptr()));
+
+    terms_str.as_ptr()
+}
+
+// Every distinct term of the eclass of `node_id` with at most `bound` nodes
+// (or levels, if `is_depth_bound`), cheapest first and space separated.
+// A `max_terms` of 0 means no limit.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_enumerate_terms(
+    ptr: *mut Context,
+    node_id: u32,
+    bound: u32,
+    is_depth_bound: bool,
+    max_terms: u32,
+) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_enumerate_terms",
+        [
+            trace::context(ptr),
+            node_id,
+            bound,
+            is_depth_bound,
+            max_terms
+        ]
+    );
+    let id = Id::from(node_id as usize);
+    let bound = if is_depth_bound {
+        TermBound::Depth(bound as usize)
+    } else {
+        TermBound::Size(bound as usize)
+    };
+    let max_terms = if max_terms == 0 {
+        None
+    } else {
+        Some(max_terms as usize)
+    };
+    let terms = context.runner.egraph.enumerate_terms(id, bound, max_terms);
+
+    let term_strs: Vec<String> = terms.iter().map(|(_, t)| t.to_string()).collect();
+    let terms_str = ManuallyDrop::new(CString::new(term_strs.join(" ")).unwrap());
+    trace_result!(trace::c_str(terms_str.as_ptr()));
+
+    terms_str.as_ptr()
+}
+
+// Every match of `pattern` as `(<eclass> (<var> <term>) ...)`, space separated,
+// where each term is the best extraction of the substituted eclass.
+// Returns null if the pattern does not parse.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_search_pattern(
+    ptr: *mut Context,
+    pattern: *const c_char,
+) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_search_pattern",
+        [trace::context(ptr), trace::c_str(pattern)]
+    );
+    let pattern: Pattern = match CStr::from_ptr(pattern).to_str().unwrap().parse() {
+        Ok(pattern) => pattern,
+        Err(_) => {
+            trace_result!(trace::Field::Null);
+            return std::ptr::null();
+        }
+    };
+
+    let match_strs: Vec<String> = search_pattern(&context.runner.egraph, &pattern)
+        .iter()
+        .map(|m| {
+            let bindings: Vec<String> = m
+                .bindings
+                .iter()
+                .map(|(var, term)| format!("({} {})", var, term))
+                .collect();
+            format!("({} {})", m.eclass, bindings.join(" "))
+        })
+        .collect();
+    let matches_str = ManuallyDrop::new(CString::new(match_strs.join(" ")).unwrap());
+    trace_result!(trace::c_str(matches_str.as_ptr()));
+
+    matches_str.as_ptr()
+}
+
+// Looks up the eclass of `expr` without adding it.
+// Returns false and leaves `id` untouched if `expr` is not in the egraph.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_lookup_expr(
+    ptr: *mut Context,
+    expr: *const c_char,
+    id: *mut u32,
+) -> bool {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!(
+        "egraph_lookup_expr",
+        [trace::context(ptr), trace::c_str(expr)]
+    );
+    let rec_expr: RecExpr = CStr::from_ptr(expr).to_str().unwrap().parse().unwrap();
+    let found = context
+        .runner
+        .egraph
+        .lookup_expr(&rec_expr)
+        .map(|found| usize::from(found) as u32);
+    trace_result!(found);
+
+    match found {
+        Some(found) => {
+            std::ptr::write(id, found);
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn egraph_find(ptr: *mut Context, node_id: u32) -> u32 {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!("egraph_find", [trace::context(ptr), node_id]);
+    let found = usize::from(context.runner.egraph.find(Id::from(node_id as usize))) as u32;
+    trace_result!(found);
+
+    found
+}
+
+fn ids_to_c_string(ids: impl IntoIterator<Item = Id>) -> *const c_char {
+    let id_strs: Vec<String> = ids.into_iter().map(|id| id.to_string()).collect();
+    trace_result!(id_strs.join(" "));
+    let ids_str = ManuallyDrop::new(CString::new(id_strs.join(" ")).unwrap());
+
+    ids_str.as_ptr()
+}
+
+// The enodes of an eclass, space separated, with children written as
+// canonical eclass ids, e.g. `(+ 3 7 8) 5`.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_nodes(ptr: *mut Context, node_id: u32) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+    let egraph = &context.runner.egraph;
+
+    trace_call!("egraph_get_nodes", [trace::context(ptr), node_id]);
+    let node_strs: Vec<String> = egraph[Id::from(node_id as usize)]
+        .nodes
+        .iter()
+        .map(|node| {
+            if node.is_leaf() {
+                node.to_string()
+            } else {
+                let children: Vec<String> = node
+                    .children()
+                    .iter()
+                    .map(|&child| egraph.find(child).to_string())
+                    .collect();
+                format!("({} {})", node, children.join(" "))
+            }
+        })
+        .collect();
+    let nodes_str = ManuallyDrop::new(CString::new(node_strs.join(" ")).unwrap());
+    trace_result!(trace::c_str(nodes_str.as_ptr()));
+
+    nodes_str.as_ptr()
+}
+
+// The distinct canonical eclass ids of the children of an eclass, space separated.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_children(ptr: *mut Context, node_id: u32) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+    let egraph = &context.runner.egraph;
+
+    trace_call!("egraph_get_children", [trace::context(ptr), node_id]);
+    let children: IndexSet<Id> = egraph[Id::from(node_id as usize)]
+        .nodes
+        .iter()
+        .flat_map(|node| node.children().iter().map(|&child| egraph.find(child)))
+        .collect();
+
+    ids_to_c_string(children)
+}
+
+// The canonical eclass ids with an enode pointing at an eclass, space separated.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_parents(ptr: *mut Context, node_id: u32) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+    let egraph = &context.runner.egraph;
+    trace_call!("egraph_get_parents", [trace::context(ptr), node_id]);
+    let id = egraph.find(Id::from(node_id as usize));
+
+    let mut parents: Vec<Id> = egraph[id]
+        .parents()
+        .map(|(_, parent)| egraph.find(parent))
+        .collect();
+    parents.sort();
+    parents.dedup();
+
+    ids_to_c_string(parents)
+}
+
+// The constant an eclass folds to, or null if it has none.
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_constant(ptr: *mut Context, node_id: u32) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+
+    trace_call!("egraph_get_constant", [trace::context(ptr), node_id]);
+    let constant = context.runner.egraph[Id::from(node_id as usize)]
+        .data
+        .as_ref()
+        .map(|(c, _)| c.to_string());
+    trace_result!(constant.clone());
+
+    match constant {
+        Some(c) => {
+            let c_str = ManuallyDrop::new(CString::new(c).unwrap());
+            c_str.as_ptr()
+        }
+        None => std::ptr::null(),
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn egraph_is_unsound_detected(ptr: *mut Context) -> bool {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
 
-    context
+    trace_call!("egraph_is_unsound_detected", [trace::context(ptr)]);
+    let is_unsound = context
         .runner
         .egraph
         .analysis
         .unsound
-        .load(Ordering::SeqCst)
+        .load(Ordering::SeqCst);
+    trace_result!(is_unsound);
+
+    is_unsound
 }
 
+// Counts applications of the rule `name`. Given the base name of a bidirectional
+// rule this is the combined count of both directions, and given a direction name
+// such as `name-rev` it is the count for that direction alone.
 #[no_mangle]
 pub unsafe extern "C" fn egraph_get_times_applied(ptr: *mut Context, name: *const c_char) -> u32 {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
-    let sym = Symbol::from(ptr_to_string(name));
+    trace_call!(
+        "egraph_get_times_applied",
+        [trace::context(ptr), trace::c_str(name)]
+    );
+    let times_applied = context.times_applied(&ptr_to_string(name)) as u32;
+    trace_result!(times_applied);
 
-    context
-        .runner
-        .iterations
-        .iter()
-        .map(|iter| *iter.applied.get(&sym).unwrap_or(&0) as u32)
-        .sum()
+    times_applied
 }
 
+// Returns `u32::MAX` when `egraph_get_simplest` would return null.
 #[no_mangle]
 pub unsafe extern "C" fn egraph_get_cost(ptr: *mut Context, node_id: u32, iter: u32) -> u32 {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
-    let ext = find_extracted(&context.runner, node_id, iter);
+    trace_call!("egraph_get_cost", [trace::context(ptr), node_id, iter]);
+    let cost = context
+        .get_simplest(Id::from(node_id as usize), iter as usize)
+        .map_or(u32::MAX, |ext| ext.cost as u32);
+    trace_result!(cost);
 
-    ext.cost as u32
+    cost
 }
 
 #[no_mangle]
@@ -374,10 +1550,369 @@ pub unsafe extern "C" fn egraph_get_size(ptr: *mut Context) -> u32 {
     // Safety: `ptr` was box allocated by `egraph_create`
     let context = ManuallyDrop::new(Box::from_raw(ptr));
 
-    context
+    trace_call!("egraph_get_size", [trace::context(ptr)]);
+    let size = context
         .runner
         .iterations
         .last()
         .map(|iteration| iteration.egraph_nodes as u32)
-        .unwrap_or_default()
+        .unwrap_or_default();
+    trace_result!(size);
+
+    size
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn egraph_get_report_json(ptr: *mut Context) -> *const c_char {
+    // Safety: `ptr` was box allocated by `egraph_create`
+    let context = ManuallyDrop::new(Box::from_raw(ptr));
+    // not compared on replay since the report includes timings
+    trace_call!("egraph_get_report_json", [trace::context(ptr)]);
+    let report = make_report(
+        &context.runner,
+        &context.limits,
+        context.extract_every,
+        context.fp_exact,
+    );
+    let report_str = ManuallyDrop::new(CString::new(report).unwrap());
+
+    report_str.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(expr: &str) -> RecExpr {
+        expr.parse().unwrap()
+    }
+
+    #[test]
+    fn multi_pattern_rules_run_with_explanations() {
+        let rules = mk_rules(&[(
+            "add-sub-to-mul-div",
+            "?a = (+ ?s ?x ?y), ?b = (- ?s ?x ?y)",
+            "?a = (* ?s ?x ?y), ?b = (/ ?s ?x ?y)",
+        )])
+        .unwrap();
+
+        let mut context = Context::new();
+        let add = parse("(+ ($Type binary64 binary64 binary64) x y)");
+        let sub = parse("(- ($Type binary64 binary64 binary64) x y)");
+        let mul = parse("(* ($Type binary64 binary64 binary64) x y)");
+        let div = parse("(/ ($Type binary64 binary64 binary64) x y)");
+        let add_id = context.add_expr(&add);
+        let sub_id = context.add_expr(&sub);
+        context.run(&rules, 3, 1000, false);
+
+        let egraph = &context.runner().egraph;
+        assert_eq!(egraph.lookup_expr(&mul), Some(egraph.find(add_id)));
+        assert_eq!(egraph.lookup_expr(&div), Some(egraph.find(sub_id)));
+        let proof = context.get_proof(&add, &mul);
+        assert!(proof.contains("add-sub-to-mul-div"), "{}", proof);
+    }
+
+    #[test]
+    fn multi_pattern_rules_can_bind_fresh_variables() {
+        let rules = mk_rules(&[(
+            "sub-to-div-of-mul",
+            "?a = (+ ?s ?x ?y), ?b = (- ?s ?x ?y)",
+            "?c = (* ?s ?x ?y), ?b = (/ ?s ?c ?y)",
+        )])
+        .unwrap();
+
+        let mut context = Context::new();
+        let sub = parse("(- ($Type binary64 binary64 binary64) x y)");
+        let goal = parse(
+            "(/ ($Type binary64 binary64 binary64) \
+             (* ($Type binary64 binary64 binary64) x y) y)",
+        );
+        context.add_expr(&parse("(+ ($Type binary64 binary64 binary64) x y)"));
+        let sub_id = context.add_expr(&sub);
+        context.run(&rules, 3, 1000, false);
+
+        let egraph = &context.runner().egraph;
+        assert_eq!(egraph.lookup_expr(&goal), Some(egraph.find(sub_id)));
+        let proof = context.get_proof(&sub, &goal);
+        assert!(proof.contains("sub-to-div-of-mul"), "{}", proof);
+    }
+
+    #[test]
+    fn fp_exact_folding_keeps_only_representable_results() {
+        let folded = |fp_exact: bool, expr: &str| {
+            let mut context = Context::new();
+            assert!(context.set_fp_exact_folding(fp_exact));
+            let id = context.add_expr(&parse(expr));
+            context.run(&[], 1, 1000, true);
+            let egraph = &context.runner().egraph;
+            egraph[egraph.find(id)]
+                .data
+                .as_ref()
+                .map(|d| d.0.to_string())
+        };
+        let third = "(/ ($Type binary64 binary64 binary64) 1 3)";
+        let quarter = "(/ ($Type binary64 binary64 binary64) 1 4)";
+        assert_eq!(folded(false, third), Some("1/3".into()));
+        assert_eq!(folded(true, third), None);
+        assert_eq!(folded(true, quarter), Some("1/4".into()));
+    }
+
+    #[test]
+    fn fp_exact_folding_is_set_before_adding_expressions() {
+        let mut context = Context::new();
+        context.add_expr(&parse("x"));
+        assert!(!context.set_fp_exact_folding(true));
+        let id = context.add_expr(&parse("(/ ($Type binary64 binary64 binary64) 1 3)"));
+        let egraph = &context.runner().egraph;
+        assert!(egraph[egraph.find(id)].data.is_some());
+    }
+
+    #[test]
+    fn rules_that_do_not_compile_are_errors() {
+        let bad = mk_rules(&[
+            ("ok", "(neg ?s ?x)", "(neg ?s ?x)"),
+            ("unbound", "(neg ?s ?x)", "(neg ?s ?y)"),
+        ]);
+        assert!(bad.is_err());
+        assert!(mk_rules(&[("unknown-applier", "?x", "#:applier nope")]).is_err());
+    }
+
+    // compiles `(name, left, right, bidirectional)` rules through the FFI
+    unsafe fn register_rules(rules: &[(&str, &str, &str, bool)]) -> *mut Ruleset {
+        let strings: Vec<[CString; 3]> = rules
+            .iter()
+            .map(|(name, left, right, _)| [name, left, right].map(|s| CString::new(*s).unwrap()))
+            .collect();
+        let mut ffi_rules: Vec<FFIRule> = strings
+            .iter()
+            .zip(rules)
+            .map(|([name, left, right], rule)| {
+                FFIRule::new(name.as_ptr(), left.as_ptr(), right.as_ptr(), rule.3)
+            })
+            .collect();
+        let ptrs: Vec<*mut FFIRule> = ffi_rules.iter_mut().map(|r| r as *mut FFIRule).collect();
+        egraph_register_rules(ptrs.as_ptr(), ptrs.len() as u32)
+    }
+
+    #[test]
+    fn bidirectional_rules_count_their_own_directions() {
+        unsafe {
+            let ruleset = register_rules(&[
+                ("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)", true),
+                ("mul-comm", "(* ?s ?a ?b)", "(* ?s ?b ?a)", false),
+                ("mul-comm-fwd", "(* ?s ?b ?a)", "(* ?s ?a ?b)", false),
+            ]);
+            assert!(!ruleset.is_null());
+
+            let ptr = egraph_create();
+            for expr in [
+                "(+ ($Type binary64 binary64 binary64) x y)",
+                "(* ($Type binary64 binary64 binary64) x y)",
+            ] {
+                egraph_add_expr(ptr, CString::new(expr).unwrap().as_ptr());
+            }
+            let (mut length, mut iterations) = (0, std::ptr::null_mut());
+            egraph_run_rules(ptr, ruleset, &mut length, &mut iterations, 3, 1000, false);
+            destroy_egraphiters(iterations);
+
+            let times =
+                |name: &str| egraph_get_times_applied(ptr, CString::new(name).unwrap().as_ptr());
+            assert!(times("add-comm-fwd") > 0);
+            assert_eq!(
+                times("add-comm"),
+                times("add-comm-fwd") + times("add-comm-rev")
+            );
+            // a unidirectional rule is counted alone, whatever other rules are named
+            let mul_comm: usize = (*ptr)
+                .runner
+                .iterations
+                .iter()
+                .map(|iter| {
+                    iter.applied
+                        .get(&Symbol::from("mul-comm"))
+                        .copied()
+                        .unwrap_or(0)
+                })
+                .sum();
+            assert_eq!(times("mul-comm"), mul_comm as u32);
+
+            egraph_destroy(ptr);
+            destroy_rules(ruleset);
+        }
+    }
+
+    #[test]
+    fn irreversible_rules_cannot_be_bidirectional() {
+        unsafe {
+            let forward_only = [
+                ("expand", "(pow ?s ?a ?b)", "#:applier pow-expand"),
+                ("mul-zero", "(* ?s ?x 0)", "0"),
+            ];
+            for (name, left, right) in forward_only {
+                let ruleset = register_rules(&[(name, left, right, false)]);
+                assert!(!ruleset.is_null());
+                destroy_rules(ruleset);
+                assert!(register_rules(&[(name, left, right, true)]).is_null());
+            }
+        }
+    }
+
+    #[test]
+    fn every_prune_policy_keeps_proofs() {
+        let rules = mk_rules(&[("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)")]).unwrap();
+        let expr = parse(
+            "(+ ($Type binary64 binary64 binary64) x (* ($Type binary64 binary64 binary64) 2 3))",
+        );
+        let goal = parse("(+ ($Type binary64 binary64 binary64) 6 x)");
+
+        for policy in [
+            PrunePolicy::Off,
+            PrunePolicy::ConstantLeaf,
+            PrunePolicy::KeepCheapest(0),
+            PrunePolicy::KeepCheapest(2),
+        ] {
+            let mut context = Context::new();
+            assert!(context.set_prune_policy(policy));
+            let id = context.add_expr(&expr);
+            context.run(&rules, 3, 1000, true);
+
+            let egraph = &context.runner().egraph;
+            assert_eq!(
+                egraph.lookup_expr(&goal),
+                Some(egraph.find(id)),
+                "{}",
+                policy
+            );
+            let proof = context.get_proof(&expr, &goal);
+            assert!(proof.contains("add-comm"), "{}: {}", policy, proof);
+            assert!(proof.contains("metadata-eval"), "{}: {}", policy, proof);
+        }
+    }
+
+    // counts its calls in `data`, a `(calls, stop_at)` pair, and stops at `stop_at`
+    unsafe extern "C" fn count_iterations(_: *const EGraphIter, data: *mut c_void) -> bool {
+        let (calls, stop_at) = &mut *(data as *mut (usize, usize));
+        *calls += 1;
+        *calls == *stop_at
+    }
+
+    fn run_with_callback(stop_at: usize) -> (Context, usize) {
+        let rules = mk_rules(&[("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)")]).unwrap();
+        let mut counts = (0, stop_at);
+        let mut context = Context::new();
+        context.set_iteration_callback(
+            Some(count_iterations),
+            &mut counts as *mut (usize, usize) as *mut c_void,
+        );
+        context.add_expr(&parse("(+ ($Type binary64 binary64 binary64) x y)"));
+        context.run(&rules, 10, 1000, false);
+        (context, counts.0)
+    }
+
+    #[test]
+    fn callbacks_see_every_iteration() {
+        let (context, calls) = run_with_callback(usize::MAX);
+        assert!(matches!(
+            context.runner().stop_reason,
+            Some(StopReason::Saturated)
+        ));
+        assert_eq!(calls, context.runner().iterations.len());
+    }
+
+    #[test]
+    fn callbacks_stop_after_the_iteration_they_see() {
+        let (context, calls) = run_with_callback(1);
+        assert_eq!(calls, 1);
+        assert_eq!(context.runner().iterations.len(), 1);
+        assert_eq!(stop_reason_code(&context.runner().stop_reason), 5);
+    }
+
+    #[test]
+    fn due_iterations_are_extracted_including_the_last() {
+        let rules = mk_rules(&[("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)")]).unwrap();
+        for every in [0, 1, 2] {
+            let mut context = Context::new();
+            context.set_extraction_interval(every);
+            let id = context.add_expr(&parse("(+ ($Type binary64 binary64 binary64) x y)"));
+            context.run(&rules, 10, 1000, false);
+
+            let iterations = &context.runner().iterations;
+            assert!(iterations.len() > 1);
+            for (i, iteration) in iterations.iter().enumerate() {
+                let due = every != 0 && i % every == 0;
+                assert_eq!(!iteration.data.extracted.is_empty(), due, "{} {}", every, i);
+            }
+            for i in 0..iterations.len() {
+                assert!(context.get_simplest(id, i).is_some(), "{} {}", every, i);
+            }
+        }
+    }
+
+    #[test]
+    fn each_phase_records_its_limits() {
+        let rules = mk_rules(&[("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)")]).unwrap();
+        let phases = [
+            Phase {
+                rules: &rules,
+                iter_limit: 1,
+                node_limit: 100,
+            },
+            Phase {
+                rules: &rules,
+                iter_limit: 5,
+                node_limit: 1000,
+            },
+        ];
+        let mut context = Context::new();
+        context.add_expr(&parse("(+ ($Type binary64 binary64 binary64) x y)"));
+        context.run_phases(&phases, false);
+
+        let limits: Vec<_> = context
+            .limits()
+            .iter()
+            .map(|l| (l.phase, l.iter_limit, l.node_limit))
+            .collect();
+        assert_eq!(limits, [(0, 1, 100), (1, 5, 1000)]);
+        // the rule needs two iterations to saturate, so phase 0 is cut short by its own limit
+        let tags: Vec<usize> = context
+            .runner()
+            .iterations
+            .iter()
+            .map(|iteration| iteration.data.phase)
+            .collect();
+        assert_eq!(tags, [0, 1], "{:?}", tags);
+        let report = make_report(context.runner(), context.limits(), 1, false);
+        assert!(
+            report.contains(r#"{"phase":1,"iter_limit":5,"node_limit":1000}"#),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn unions_are_extracted_and_other_classes_are_not() {
+        let rules = mk_rules(&[("add-comm", "(+ ?s ?a ?b)", "(+ ?s ?b ?a)")]).unwrap();
+        let mut context = Context::new();
+        let a = parse("(+ ($Type binary64 binary64 binary64) x y)");
+        let b = parse("(neg ($Type binary64 binary64) z)");
+        let id = context.union_exprs(&a, &b, "caller-knows-best");
+        context.run(&rules, 10, 1000, false);
+
+        let best = &context.get_simplest(id, usize::MAX).unwrap().best;
+        assert_eq!(best.to_string(), b.to_string());
+        let x = context.runner().egraph.lookup_expr(&parse("x")).unwrap();
+        assert!(context.get_simplest(x, usize::MAX).is_none());
+    }
+
+    #[test]
+    fn proofs_cite_union_reasons() {
+        let mut context = Context::new();
+        let a = parse("(+ ($Type binary64 binary64 binary64) x y)");
+        let b = parse("(* ($Type binary64 binary64 binary64) x y)");
+        let id = context.union_exprs(&a, &b, "caller-knows-best");
+
+        assert_eq!(context.runner().egraph.lookup_expr(&b), Some(id));
+        let proof = context.get_proof(&a, &b);
+        assert!(proof.contains("caller-knows-best"), "{}", proof);
+    }
 }
@@ -1,4 +1,6 @@
 use egg::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use num_bigint::BigInt;
@@ -7,6 +9,10 @@ use num_rational::Ratio;
 use num_traits::{One, Pow, Signed, Zero};
 use std::str::FromStr;
 
+use crate::extract::DeterministicExtractor;
+use crate::poly::{normalize_polynomial, normalize_polynomials};
+use crate::sexp::split_sexps;
+
 pub type Constant = num_rational::BigRational;
 pub type RecExpr = egg::RecExpr<Math>;
 pub type Pattern = egg::Pattern<Math>;
@@ -17,22 +23,134 @@ pub type Iteration = egg::Iteration<IterData>;
 
 pub struct IterData {
     pub extracted: Vec<(Id, Extracted)>,
+    // index of the phase the iteration belongs to, see `Context::run_phases`
+    pub phase: usize,
 }
 
+#[derive(Clone)]
 pub struct Extracted {
     pub best: RecExpr,
     pub cost: usize,
 }
 
+// operators and type signatures that extraction must never pick,
+// a type is either a whole `($Type ...)` signature or an output representation
+#[derive(Clone, Debug, Default)]
+pub struct DenyList {
+    pub ops: Vec<String>,
+    pub types: Vec<String>,
+}
+
+impl DenyList {
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty() && self.types.is_empty()
+    }
+
+    // canonical ids of the `$Type` classes matching a denied type
+    fn signature_classes(&self, egraph: &EGraph) -> HashSet<Id> {
+        type_classes(egraph, &self.types)
+    }
+}
+
+// Canonical ids of the `$Type` classes matching any of `types`, where a type
+// is either a whole `($Type ...)` signature or an output representation.
+// Looks classes up instead of comparing printed terms, so a signature
+// matches however its class would be extracted.
+pub fn type_classes(egraph: &EGraph, types: &[String]) -> HashSet<Id> {
+    let mut sigs = HashSet::default();
+    if types.is_empty() {
+        return sigs;
+    }
+
+    for ty in types.iter().filter(|ty| ty.starts_with('(')) {
+        if let Ok(sig) = ty.parse::<RecExpr>() {
+            if let Some(id) = egraph.lookup_expr(&sig) {
+                sigs.insert(egraph.find(id));
+            }
+        }
+    }
+
+    let is_repr = |id: Id| {
+        egraph[id].nodes.iter().any(|n| match n {
+            Math::Symbol(repr) => types.iter().any(|t| t == repr.as_str()),
+            _ => false,
+        })
+    };
+    for class in egraph.classes() {
+        for node in &class.nodes {
+            match node {
+                Math::Other(op, children) if op.as_str() == "$Type" => {
+                    if children.first().map_or(false, |&id| is_repr(id)) {
+                        sigs.insert(class.id);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    sigs
+}
+
+// Cost of `(pow _ p)` where p is a fraction, on top of its size, under
+// `AltCost::with_fractional_pow_penalty`. Large enough to outweigh any
+// realistic term, but finite so that such terms stay distinct from denied
+// ones, which cost `usize::MAX`, and fit in the `u32` costs reported over FFI.
+pub const FRACTIONAL_POW_PENALTY: usize = 1 << 20;
+
 // cost function similar to AstSize except it will
 // penalize `(pow _ p)` where p is a fraction
+// and never pick anything on the deny list
+#[derive(Clone)]
 pub struct AltCost<'a> {
     pub egraph: &'a EGraph,
+    denied_ops: HashSet<String>,
+    denied_sigs: HashSet<Id>,
+    // cost of a fractional `pow` node itself, `usize::MAX` unless penalized finitely
+    fractional_pow_cost: usize,
 }
 
 impl<'a> AltCost<'a> {
     pub fn new(egraph: &'a EGraph) -> Self {
-        Self { egraph }
+        Self {
+            egraph,
+            denied_ops: Default::default(),
+            denied_sigs: Default::default(),
+            fractional_pow_cost: usize::MAX,
+        }
+    }
+
+    pub fn with_deny_list(egraph: &'a EGraph, deny: &DenyList) -> Self {
+        Self {
+            denied_ops: deny.ops.iter().cloned().collect(),
+            denied_sigs: deny.signature_classes(egraph),
+            ..Self::new(egraph)
+        }
+    }
+
+    // charges `FRACTIONAL_POW_PENALTY` for a fractional `pow` instead of `usize::MAX`,
+    // so that only denied terms are unextractable
+    pub fn with_fractional_pow_penalty(self) -> Self {
+        Self {
+            fractional_pow_cost: FRACTIONAL_POW_PENALTY,
+            ..self
+        }
+    }
+
+    fn is_denied(&self, enode: &Math) -> bool {
+        if let Some(op) = enode.op_name() {
+            if self.denied_ops.contains(op) {
+                return true;
+            }
+        }
+
+        // the first child of an operator is its `$Type` signature
+        match enode.children().first() {
+            Some(&sig) if !self.denied_sigs.is_empty() => {
+                self.denied_sigs.contains(&self.egraph.find(sig))
+            }
+            _ => false,
+        }
     }
 }
 
@@ -43,21 +161,27 @@ impl<'a> CostFunction<Math> for AltCost<'a> {
     where
         C: FnMut(Id) -> Self::Cost,
     {
+        if self.is_denied(enode) {
+            return usize::MAX;
+        }
+
+        let mut cost = 1;
         if let Math::Pow([_, _, i]) = enode {
             if let Some((n, _reason)) = &self.egraph[*i].data {
                 if !n.denom().is_one() && n.denom().is_odd() {
-                    return usize::MAX;
+                    cost = self.fractional_pow_cost;
                 }
             }
         }
 
-        enode.fold(1, |sum, id| usize::saturating_add(sum, costs(id)))
+        enode.fold(cost, |sum, id| usize::saturating_add(sum, costs(id)))
     }
 }
 
-impl IterationData<Math, ConstantFold> for IterData {
-    fn make(runner: &Runner) -> Self {
-        let extractor = Extractor::new(&runner.egraph, AltCost::new(&runner.egraph));
+impl IterData {
+    // the best term of every root in the runner's current egraph
+    pub fn extract(runner: &Runner) -> Self {
+        let extractor = DeterministicExtractor::new(&runner.egraph, AltCost::new(&runner.egraph));
         let extracted = runner
             .roots
             .iter()
@@ -67,7 +191,20 @@ impl IterationData<Math, ConstantFold> for IterData {
                 (root, ext)
             })
             .collect();
-        Self { extracted }
+        Self {
+            extracted,
+            phase: runner.egraph.analysis.phase,
+        }
+    }
+}
+
+impl IterationData<Math, ConstantFold> for IterData {
+    // left empty, `Context::run_phases` extracts the iterations that are due
+    fn make(runner: &Runner) -> Self {
+        Self {
+            extracted: vec![],
+            phase: runner.egraph.analysis.phase,
+        }
     }
 }
 
@@ -97,24 +234,257 @@ define_language! {
     }
 }
 
+impl Math {
+    // operator name as written in egg IR, `None` for constants and symbols
+    pub fn op_name(&self) -> Option<&str> {
+        Some(match self {
+            Math::Add(_) => "+",
+            Math::Sub(_) => "-",
+            Math::Mul(_) => "*",
+            Math::Div(_) => "/",
+            Math::Pow(_) => "pow",
+            Math::Neg(_) => "neg",
+            Math::Sqrt(_) => "sqrt",
+            Math::Fabs(_) => "fabs",
+            Math::Ceil(_) => "ceil",
+            Math::Floor(_) => "floor",
+            Math::Round(_) => "round",
+            Math::Log(_) => "log",
+            Math::Cbrt(_) => "cbrt",
+            Math::Other(op, _) => op.as_str(),
+            Math::Constant(_) | Math::Symbol(_) => return None,
+        })
+    }
+}
+
 pub struct ConstantFold {
     pub unsound: AtomicBool,
     pub constant_fold: bool,
-    pub prune: bool,
+    // only fold when the floating-point operation would compute the same value,
+    // set from `Context::set_fp_exact_folding` before nodes are added or rules run
+    pub fp_exact: bool,
+    pub prune: PrunePolicy,
+    // phase of the run in progress, recorded with each iteration
+    pub phase: usize,
 }
 
 impl Default for ConstantFold {
     fn default() -> Self {
         Self {
             constant_fold: true,
-            prune: true,
+            fp_exact: false,
+            prune: PrunePolicy::ConstantLeaf,
+            phase: 0,
             unsound: AtomicBool::new(false),
         }
     }
 }
 
+// which enodes to keep in a class once it has been folded to a constant,
+// the constant leaf is always kept and proofs do not depend on the rest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrunePolicy {
+    // keep every enode
+    Off,
+    // keep only leaves, which is the constant itself
+    ConstantLeaf,
+    // keep the leaves and the `k` other enodes cheapest under `AltCost`,
+    // see `PrunePolicy::estimate_cost`
+    KeepCheapest(usize),
+}
+
+// how many levels of children `PrunePolicy::estimate_cost` looks through
+const PRUNE_COST_DEPTH: usize = 2;
+
+impl PrunePolicy {
+    // the FFI encoding: 0 off, 1 constant leaf, 2 keep cheapest `k`
+    pub fn from_code(code: u32, k: u32) -> Option<Self> {
+        match code {
+            0 => Some(PrunePolicy::Off),
+            1 => Some(PrunePolicy::ConstantLeaf),
+            2 => Some(PrunePolicy::KeepCheapest(k as usize)),
+            _ => None,
+        }
+    }
+
+    fn prune(&self, egraph: &mut EGraph, class_id: Id) {
+        match *self {
+            PrunePolicy::Off => (),
+            PrunePolicy::ConstantLeaf => egraph[class_id].nodes.retain(|n| n.is_leaf()),
+            PrunePolicy::KeepCheapest(k) => {
+                let graph: &EGraph = egraph;
+                let mut class_costs = HashMap::new();
+                let mut ranked: Vec<(usize, &Math)> = graph[class_id]
+                    .nodes
+                    .iter()
+                    .filter(|n| !n.is_leaf())
+                    .map(|n| {
+                        let cost =
+                            Self::estimate_cost(graph, n, PRUNE_COST_DEPTH, &mut class_costs);
+                        (cost, n)
+                    })
+                    .collect();
+                // stable, so equally cheap enodes keep their order
+                ranked.sort_by_key(|(cost, _)| *cost);
+                let kept: HashSet<Math> =
+                    ranked.into_iter().take(k).map(|(_, n)| n.clone()).collect();
+                egraph[class_id]
+                    .nodes
+                    .retain(|n| n.is_leaf() || kept.contains(n))
+            }
+        }
+    }
+
+    // The `AltCost` of `enode` looking `depth` levels into its children.
+    // Pruning runs every time a constant class changes, too often to extract
+    // the whole egraph, so deeper classes count as leaves and constant
+    // classes as their constant, which is the only enode they need to keep.
+    // `class_costs` holds the classes already estimated, by class and depth,
+    // so enodes sharing a child only look through it once.
+    fn estimate_cost(
+        egraph: &EGraph,
+        enode: &Math,
+        depth: usize,
+        class_costs: &mut HashMap<(Id, usize), usize>,
+    ) -> usize {
+        AltCost::new(egraph).cost(enode, |child| {
+            if depth == 0 || egraph[child].data.is_some() {
+                1
+            } else {
+                Self::estimate_class_cost(egraph, child, depth - 1, class_costs)
+            }
+        })
+    }
+
+    // the cheapest `estimate_cost` of the enodes of `class`
+    fn estimate_class_cost(
+        egraph: &EGraph,
+        class: Id,
+        depth: usize,
+        class_costs: &mut HashMap<(Id, usize), usize>,
+    ) -> usize {
+        let key = (egraph.find(class), depth);
+        if let Some(&cost) = class_costs.get(&key) {
+            return cost;
+        }
+
+        let cost = egraph[class]
+            .nodes
+            .iter()
+            .map(|n| Self::estimate_cost(egraph, n, depth, class_costs))
+            .min()
+            .unwrap_or(usize::MAX);
+        class_costs.insert(key, cost);
+        cost
+    }
+}
+
+impl fmt::Display for PrunePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrunePolicy::Off => write!(f, "off"),
+            PrunePolicy::ConstantLeaf => write!(f, "constant-leaf"),
+            PrunePolicy::KeepCheapest(k) => write!(f, "cheapest-{}", k),
+        }
+    }
+}
+
+impl FromStr for PrunePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(PrunePolicy::Off),
+            "constant-leaf" => Ok(PrunePolicy::ConstantLeaf),
+            _ => s
+                .strip_prefix("cheapest-")
+                .and_then(|k| k.parse().ok())
+                .map(PrunePolicy::KeepCheapest)
+                .ok_or_else(|| format!("unknown pruning policy `{}`", s)),
+        }
+    }
+}
+
+pub fn repr_symbol(egraph: &EGraph, id: Id) -> Option<Symbol> {
+    egraph[id].nodes.iter().find_map(|node| match node {
+        Math::Symbol(s) => Some(*s),
+        _ => None,
+    })
+}
+
+// the representation `T` if `sig` is `($Type T T ...)` with `arity` arguments
+pub fn homogeneous_repr(egraph: &EGraph, sig: Id, arity: usize) -> Option<Symbol> {
+    egraph[sig].nodes.iter().find_map(|node| match node {
+        Math::Other(op, children) if op.as_str() == "$Type" && children.len() == arity + 1 => {
+            let reprs: Vec<Symbol> = children
+                .iter()
+                .map(|&child| repr_symbol(egraph, child))
+                .collect::<Option<_>>()?;
+            reprs.iter().all(|r| *r == reprs[0]).then_some(reprs[0])
+        }
+        _ => None,
+    })
+}
+
+// an IEEE 754 binary format: bits of precision and the range of exponents,
+// where every finite value is `m * 2^e` with `min_exponent <= e` and `|m * 2^e| < 2^max_exponent`
+#[derive(Clone, Copy, Debug)]
+pub struct FloatFormat {
+    pub precision: u64,
+    pub min_exponent: i64,
+    pub max_exponent: i64,
+}
+
+pub fn float_format(repr: &str) -> Option<FloatFormat> {
+    match repr {
+        "binary64" => Some(FloatFormat {
+            precision: 53,
+            min_exponent: -1074,
+            max_exponent: 1024,
+        }),
+        "binary32" => Some(FloatFormat {
+            precision: 24,
+            min_exponent: -149,
+            max_exponent: 128,
+        }),
+        _ => None,
+    }
+}
+
+// whether `c` is a finite value of `format`, so rounding it changes nothing
+pub fn is_representable(c: &Constant, format: FloatFormat) -> bool {
+    if c.is_zero() {
+        return true;
+    }
+
+    // the denominator of a reduced ratio is positive
+    let denom_zeros = c.denom().trailing_zeros().unwrap_or(0);
+    if *c.denom() != BigInt::one() << denom_zeros {
+        return false;
+    }
+
+    let numer = c.numer().abs();
+    let numer_zeros = numer.trailing_zeros().unwrap_or(0);
+    let bits = (numer >> numer_zeros).bits();
+    let exponent = numer_zeros as i64 - denom_zeros as i64;
+
+    bits <= format.precision
+        && exponent >= format.min_exponent
+        && exponent + bits as i64 <= format.max_exponent
+}
+
+// the format an operator computes in when folding floating-point exactly,
+// `None` if the operator is not over a single binary format
+fn fp_exact_format(egraph: &EGraph, enode: &Math) -> Option<FloatFormat> {
+    let (sig, args) = enode.children().split_first()?;
+    let repr = homogeneous_repr(egraph, *sig, args.len())?;
+    float_format(repr.as_str())
+}
+
 impl Analysis<Math> for ConstantFold {
-    type Data = Option<(Constant, (PatternAst<Math>, Subst))>;
+    // the constant and the node it was folded from,
+    // which `modify` turns into a justification if it needs one
+    type Data = Option<(Constant, Math)>;
     fn make(egraph: &mut EGraph, enode: &Math) -> Self::Data {
         if !egraph.analysis.constant_fold {
             return None;
@@ -129,7 +499,20 @@ impl Analysis<Math> for ConstantFold {
             }
         };
 
-        Some((
+        // in fp-exact mode the inputs and result must be values of the operator's format
+        let fp_format = if egraph.analysis.fp_exact && !enode.is_leaf() {
+            let format = fp_exact_format(egraph, enode)?;
+            for arg in &enode.children()[1..] {
+                if !is_representable(&x(arg)?, format) {
+                    return None;
+                }
+            }
+            Some(format)
+        } else {
+            None
+        };
+
+        let data = Some((
             match enode {
                 Math::Constant(c) => c.clone(),
 
@@ -196,33 +579,13 @@ impl Analysis<Math> for ConstantFold {
 
                 _ => return None,
             },
-            {
-                let mut pattern: PatternAst<Math> = Default::default();
-                let mut var_counter = 0;
-                let mut subst: Subst = Default::default();
-                enode.for_each(|child| {
-                    if let Some(constant) = x(&child) {
-                        pattern.add(ENodeOrVar::ENode(Math::Constant(constant)));
-                    } else {
-                        let var = ("?".to_string() + &var_counter.to_string())
-                            .parse()
-                            .unwrap();
-                        pattern.add(ENodeOrVar::Var(var));
-                        subst.insert(var, child);
-                        var_counter += 1;
-                    }
-                });
-                let mut counter = 0;
-                let mut head = enode.clone();
-                head.update_children(|_child| {
-                    let res = Id::from(counter);
-                    counter += 1;
-                    res
-                });
-                pattern.add(ENodeOrVar::ENode(head));
-                (pattern, subst)
-            },
-        ))
+            enode.clone(),
+        ));
+
+        match (&data, fp_format) {
+            (Some((c, _)), Some(format)) if !is_representable(c, format) => None,
+            _ => data,
+        }
     }
 
     fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
@@ -244,28 +607,443 @@ impl Analysis<Math> for ConstantFold {
 
     fn modify(egraph: &mut EGraph, class_id: Id) {
         let class = &mut egraph[class_id];
-        if let Some((c, (pat, subst))) = class.data.clone() {
-            egraph.union_instantiations(
-                &pat,
-                &format!("{}", c).parse().unwrap(),
-                &subst,
-                "metadata-eval".to_string(),
-            );
-
-            if egraph.analysis.prune {
-                egraph[class_id].nodes.retain(|n| n.is_leaf())
+        if let Some((c, enode)) = class.data.clone() {
+            let folded = egraph.lookup(Math::Constant(c.clone()));
+            if folded.map(|id| egraph.find(id)) != Some(egraph.find(class_id)) {
+                let (pat, subst) = fold_justification(egraph, &enode);
+                egraph.union_instantiations(
+                    &pat,
+                    &format!("{}", c).parse().unwrap(),
+                    &subst,
+                    "metadata-eval".to_string(),
+                );
+            }
+
+            let policy = egraph.analysis.prune;
+            policy.prune(egraph, class_id);
+        }
+    }
+}
+
+// the pattern a constant was folded from,
+// with constant children inlined and the rest as variables
+fn fold_justification(egraph: &EGraph, enode: &Math) -> (PatternAst<Math>, Subst) {
+    let mut pattern: PatternAst<Math> = Default::default();
+    let mut var_counter = 0;
+    let mut subst: Subst = Default::default();
+    enode.for_each(|child| {
+        if let Some((constant, _)) = &egraph[child].data {
+            pattern.add(ENodeOrVar::ENode(Math::Constant(constant.clone())));
+        } else {
+            let var = ("?".to_string() + &var_counter.to_string())
+                .parse()
+                .unwrap();
+            pattern.add(ENodeOrVar::Var(var));
+            subst.insert(var, egraph.find(child));
+            var_counter += 1;
+        }
+    });
+    let mut counter = 0;
+    let mut head = enode.clone();
+    head.update_children(|_child| {
+        let res = Id::from(counter);
+        counter += 1;
+        res
+    });
+    pattern.add(ENodeOrVar::ENode(head));
+    (pattern, subst)
+}
+
+// side conditions for rules loaded from a rule file
+pub enum RuleCondition {
+    // both patterns already exist and are equivalent
+    Equal(Pattern, Pattern),
+    // the class has a known constant value
+    Constant(Var),
+    // the class has a known, non-zero constant value
+    NonZero(Var),
+}
+
+// like instantiating a pattern, but never adds to the egraph
+fn lookup_instantiation(egraph: &EGraph, pat: &PatternAst<Math>, subst: &Subst) -> Option<Id> {
+    let mut ids: Vec<Id> = Vec::with_capacity(pat.as_ref().len());
+    for node in pat.as_ref() {
+        let id = match node {
+            ENodeOrVar::Var(v) => subst[*v],
+            ENodeOrVar::ENode(n) => {
+                let mut n = n.clone();
+                n.update_children(|child| ids[usize::from(child)]);
+                egraph.lookup(n)?
             }
+        };
+        ids.push(id);
+    }
+    ids.last().copied()
+}
+
+impl Condition<Math, ConstantFold> for RuleCondition {
+    fn check(&self, egraph: &mut EGraph, _eclass: Id, subst: &Subst) -> bool {
+        match self {
+            RuleCondition::Equal(a, b) => {
+                let a = lookup_instantiation(egraph, &a.ast, subst);
+                let b = lookup_instantiation(egraph, &b.ast, subst);
+                match (a, b) {
+                    (Some(a), Some(b)) => egraph.find(a) == egraph.find(b),
+                    _ => false,
+                }
+            }
+            RuleCondition::Constant(v) => egraph[subst[*v]].data.is_some(),
+            RuleCondition::NonZero(v) => {
+                matches!(&egraph[subst[*v]].data, Some((c, _)) if !c.is_zero())
+            }
+        }
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        match self {
+            RuleCondition::Equal(a, b) => {
+                let mut vars = a.vars();
+                vars.extend(b.vars());
+                vars
+            }
+            RuleCondition::Constant(v) | RuleCondition::NonZero(v) => vec![*v],
+        }
+    }
+}
+
+// largest exponent `pow-expand` turns into repeated multiplication
+pub const MAX_POW_EXPANSION: u32 = 8;
+
+// Rust appliers a rule can name as its right-hand side with `#:applier <name>`,
+// for rewrites that cannot be written as a pattern.
+// Each reads fixed variables that the left-hand side must bind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamedApplier {
+    // `?a` to the `?b` as repeated multiplication with signature `?s`,
+    // for a constant integer `?b` from 2 to `MAX_POW_EXPANSION`
+    PowExpand,
+    // `(* ?s <?a * ?b> ?x)` for constant `?a` and `?b`
+    FoldCoefficients,
+    // `(* ?s <1 / ?c> ?x)` for a non-zero constant `?c`
+    NormalizeConstantFactor,
+    // the matched class as a normalized sum of products, see `poly`
+    PolyNormalize,
+}
+
+pub const NAMED_APPLIERS: &[(&str, NamedApplier)] = &[
+    ("pow-expand", NamedApplier::PowExpand),
+    ("fold-coefficients", NamedApplier::FoldCoefficients),
+    (
+        "normalize-constant-factor",
+        NamedApplier::NormalizeConstantFactor,
+    ),
+    ("poly-normalize", NamedApplier::PolyNormalize),
+];
+
+fn constant_of<'a>(egraph: &'a EGraph, subst: &Subst, var: &str) -> Option<&'a Constant> {
+    let var: Var = var.parse().unwrap();
+    egraph[subst[var]].data.as_ref().map(|(c, _)| c)
+}
+
+impl NamedApplier {
+    pub fn from_name(name: &str) -> Option<Self> {
+        NAMED_APPLIERS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, applier)| *applier)
+    }
+
+    fn var_names(&self) -> &'static [&'static str] {
+        match self {
+            NamedApplier::PowExpand => &["?s", "?a", "?b"],
+            NamedApplier::FoldCoefficients => &["?s", "?a", "?b", "?x"],
+            NamedApplier::NormalizeConstantFactor => &["?s", "?c", "?x"],
+            NamedApplier::PolyNormalize => &[],
         }
     }
+
+    // the pattern to union with the match, or `None` if the applier does not apply
+    fn result(&self, egraph: &EGraph, subst: &Subst) -> Option<PatternAst<Math>> {
+        let result = match self {
+            NamedApplier::PowExpand => {
+                let b = constant_of(egraph, subst, "?b")?;
+                if !b.is_integer() {
+                    return None;
+                }
+                let n: u32 = b.to_integer().try_into().ok()?;
+                if !(2..=MAX_POW_EXPANSION).contains(&n) {
+                    return None;
+                }
+                (1..n).fold("?a".to_string(), |acc, _| format!("(* ?s ?a {})", acc))
+            }
+            NamedApplier::FoldCoefficients => {
+                let a = constant_of(egraph, subst, "?a")?;
+                let b = constant_of(egraph, subst, "?b")?;
+                format!("(* ?s {} ?x)", a * b)
+            }
+            NamedApplier::NormalizeConstantFactor => {
+                let c = constant_of(egraph, subst, "?c")?;
+                if c.is_zero() {
+                    return None;
+                }
+                format!("(* ?s {} ?x)", c.recip())
+            }
+            // works on the whole class rather than a pattern, see `apply_one`
+            NamedApplier::PolyNormalize => return None,
+        };
+        Some(result.parse().unwrap())
+    }
 }
 
-pub fn mk_rules(tuples: &[(&str, &str, &str)]) -> Vec<Rewrite> {
+impl Applier<Math, ConstantFold> for NamedApplier {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph,
+        eclass: Id,
+        subst: &Subst,
+        searcher_ast: Option<&PatternAst<Math>>,
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        // only conditional rules apply `poly-normalize` one match at a time
+        if let NamedApplier::PolyNormalize = self {
+            return if normalize_polynomial(egraph, eclass, subst, searcher_ast, rule_name) {
+                vec![eclass]
+            } else {
+                vec![]
+            };
+        }
+
+        let result = match self.result(egraph, subst) {
+            Some(result) => result,
+            None => return vec![],
+        };
+
+        let changed = match searcher_ast {
+            Some(ast) => {
+                egraph
+                    .union_instantiations(ast, &result, subst, rule_name)
+                    .1
+            }
+            None => {
+                let id = egraph.add_instantiation(&result, subst);
+                egraph.union_trusted(eclass, id, rule_name)
+            }
+        };
+
+        if changed {
+            vec![eclass]
+        } else {
+            vec![]
+        }
+    }
+
+    // `poly-normalize` builds polynomials once for all the matches of an iteration,
+    // the others apply each match in turn like egg's default
+    fn apply_matches(
+        &self,
+        egraph: &mut EGraph,
+        matches: &[SearchMatches<Math>],
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        if let NamedApplier::PolyNormalize = self {
+            return normalize_polynomials(egraph, matches, rule_name);
+        }
+
+        let with_ast = egraph.are_explanations_enabled();
+        let mut changed = vec![];
+        for m in matches {
+            let ast = m.ast.as_ref().filter(|_| with_ast).map(|ast| ast.as_ref());
+            for subst in &m.substs {
+                changed.extend(self.apply_one(egraph, m.eclass, subst, ast, rule_name));
+            }
+        }
+        changed
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.var_names()
+            .iter()
+            .map(|v| v.parse().unwrap())
+            .collect()
+    }
+}
+
+// Splits a multi-pattern `?x = <pattern>, ?y = <pattern>, ...` into its bindings,
+// returns `None` for a plain pattern.
+// Unlike egg's parser this never splits inside a pattern,
+// so operators such as `==` or `<=` are fine.
+fn multi_pattern_bindings(side: &str) -> Result<Option<Vec<(Var, &str)>>, String> {
+    let items: Vec<&str> = split_sexps(side)?
+        .into_iter()
+        .map(|item| item.trim_matches(','))
+        .filter(|item| !item.is_empty())
+        .collect();
+
+    if items.len() < 2 {
+        return Ok(None);
+    }
+
+    items
+        .chunks(3)
+        .map(|binding| match binding {
+            [var, "=", pattern] => var
+                .parse()
+                .map(|var| (var, *pattern))
+                .map_err(|_| format!("expected a pattern variable, got `{}`", var)),
+            _ => Err(format!(
+                "expected `?var = <pattern>`, got `{}`",
+                binding.join(" ")
+            )),
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map(Some)
+}
+
+fn parse_multi_pattern_asts(
+    bindings: Vec<(Var, &str)>,
+) -> Result<Vec<(Var, PatternAst<Math>)>, String> {
+    bindings
+        .into_iter()
+        .map(|(var, pattern)| {
+            PatternAst::from_str(pattern)
+                .map(|ast| (var, ast))
+                .map_err(|e| format!("bad pattern `{}`: {}", pattern, e))
+        })
+        .collect()
+}
+
+fn parse_multi_pattern(bindings: Vec<(Var, &str)>) -> Result<MultiPattern<Math>, String> {
+    Ok(MultiPattern::new(parse_multi_pattern_asts(bindings)?))
+}
+
+// Right side of a multi-pattern rule. Instantiates each `?x = <pattern>` in turn
+// and unions it with `?x`, or binds `?x` for later patterns if the match did not.
+// Unlike egg's `MultiPattern`, which merges with `union`, this justifies every
+// merge by the rule, so it works with explanations enabled.
+#[derive(Debug)]
+pub struct MultiPatternApplier {
+    asts: Vec<(Var, PatternAst<Math>)>,
+}
+
+impl Applier<Math, ConstantFold> for MultiPatternApplier {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph,
+        _eclass: Id,
+        subst: &Subst,
+        _searcher_ast: Option<&PatternAst<Math>>,
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        let mut subst = subst.clone();
+        let mut changed = vec![];
+        for (var, ast) in &self.asts {
+            let id = egraph.add_instantiation(ast, &subst);
+            if let Some(bound) = subst.insert(*var, id) {
+                if egraph.union_trusted(bound, id, rule_name) {
+                    changed.push(bound);
+                }
+            }
+        }
+        changed
+    }
+
+    // like egg's `MultiPattern::vars`, only variables a pattern uses before
+    // an earlier binding could have bound them must come from the searcher
+    fn vars(&self) -> Vec<Var> {
+        let mut bound = HashSet::new();
+        let mut vars = vec![];
+        for (var, ast) in &self.asts {
+            for node in ast.as_ref() {
+                if let ENodeOrVar::Var(v) = node {
+                    if !bound.contains(v) {
+                        vars.push(*v);
+                    }
+                }
+            }
+            bound.insert(*var);
+        }
+        vars.sort();
+        vars.dedup();
+        vars
+    }
+}
+
+fn new_rewrite<S, A>(
+    name: &str,
+    searcher: S,
+    applier: A,
+    condition: Option<RuleCondition>,
+) -> Result<Rewrite, String>
+where
+    S: Searcher<Math, ConstantFold> + Send + Sync + 'static,
+    A: Applier<Math, ConstantFold> + Send + Sync + 'static,
+{
+    match condition {
+        None => Rewrite::new(name, searcher, applier),
+        Some(condition) => Rewrite::new(name, searcher, ConditionalApplier { condition, applier }),
+    }
+}
+
+// Builds a single rewrite. The right side may be `#:applier <name>`
+// to use a `NamedApplier`. Otherwise either both sides are plain patterns,
+// or both are multi-patterns where the left side matches across several
+// eclasses and each `?x = <pattern>` on the right side unions with `?x`.
+pub fn mk_rule(name: &str, left: &str, right: &str) -> Result<Rewrite, String> {
+    mk_conditional_rule(name, left, right, None)
+}
+
+// Like `mk_rule`, but only applies where `condition` holds.
+pub fn mk_conditional_rule(
+    name: &str,
+    left: &str,
+    right: &str,
+    condition: Option<RuleCondition>,
+) -> Result<Rewrite, String> {
+    if let Some(applier_name) = right.trim().strip_prefix("#:applier") {
+        let applier_name = applier_name.trim();
+        let applier = NamedApplier::from_name(applier_name)
+            .ok_or_else(|| format!("rule {}: unknown applier `{}`", name, applier_name))?;
+        return match multi_pattern_bindings(left)? {
+            None => {
+                let left = Pattern::from_str(left).map_err(|e| e.to_string())?;
+                new_rewrite(name, left, applier, condition)
+            }
+            Some(left) => new_rewrite(name, parse_multi_pattern(left)?, applier, condition),
+        };
+    }
+
+    match (
+        multi_pattern_bindings(left)?,
+        multi_pattern_bindings(right)?,
+    ) {
+        (None, None) => {
+            let left = Pattern::from_str(left).map_err(|e| e.to_string())?;
+            let right = Pattern::from_str(right).map_err(|e| e.to_string())?;
+            new_rewrite(name, left, right, condition)
+        }
+        (Some(left), Some(right)) => {
+            let left = parse_multi_pattern(left)?;
+            let right = MultiPatternApplier {
+                asts: parse_multi_pattern_asts(right)?,
+            };
+            new_rewrite(name, left, right, condition)
+        }
+        _ => Err(format!(
+            "rule {}: a multi-pattern rule needs multi-patterns on both sides",
+            name
+        )),
+    }
+}
+
+// names of the forward and backward rewrites of a bidirectional rule
+pub fn direction_names(base: &str) -> [String; 2] {
+    [format!("{}-fwd", base), format!("{}-rev", base)]
+}
+
+// Builds every rule, or reports the first one that does not compile.
+pub fn mk_rules(tuples: &[(&str, &str, &str)]) -> Result<Vec<Rewrite>, String> {
     tuples
         .iter()
-        .map(|(name, left, right)| {
-            let left = Pattern::from_str(left).unwrap();
-            let right = Pattern::from_str(right).unwrap();
-            Rewrite::new(*name, left, right).unwrap()
-        })
+        .map(|(name, left, right)| mk_rule(name, left, right))
         .collect()
 }
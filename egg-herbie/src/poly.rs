@@ -0,0 +1,525 @@
+// A normal form of sparse polynomials for eclasses built from `+`, `-`, `*`,
+// `neg` and small integer `pow` over a single representation. Any other eclass
+// is an opaque variable, and variables are ordered by the best term of their
+// class, so the form never depends on eclass ids.
+//
+// An eclass usually has several polynomial enodes. Only those whose arguments
+// all have a strictly cheaper best term than the class are expanded, so the
+// walk never goes around a cycle, and the first one in a fixed order that does
+// not grow too large wins. Each class thus gets a single polynomial, whichever
+// class the walk started from. This is a sound normalization but not a complete
+// one: a class whose expandable enodes do not reveal its polynomial, such as one
+// that also holds a leaf, stays opaque.
+
+use egg::{ENodeOrVar, Id, PatternAst, SearchMatches, Subst, Symbol, Var};
+use num_traits::{One, Zero};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::extract::DeterministicExtractor;
+use crate::math::*;
+
+// largest `pow` exponent expanded into a product
+pub const MAX_POLY_EXPONENT: u32 = 8;
+// polynomials with more terms are left alone
+pub const MAX_POLY_TERMS: usize = 64;
+
+// an opaque eclass, ordered by its best term, which no other class shares
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PolyVar {
+    term: String,
+    id: Id,
+}
+
+// variables, sorted, with their exponents
+type Monomial = Vec<(PolyVar, u32)>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Poly {
+    terms: BTreeMap<Monomial, Constant>,
+}
+
+fn mul_monomials(a: &Monomial, b: &Monomial) -> Monomial {
+    let mut product: BTreeMap<PolyVar, u32> = a.iter().cloned().collect();
+    for (var, n) in b {
+        *product.entry(var.clone()).or_default() += n;
+    }
+    product.into_iter().collect()
+}
+
+impl Poly {
+    fn constant(c: Constant) -> Self {
+        let mut poly = Self::default();
+        if !c.is_zero() {
+            poly.terms.insert(vec![], c);
+        }
+        poly
+    }
+
+    fn var(var: PolyVar) -> Self {
+        let mut poly = Self::default();
+        poly.terms.insert(vec![(var, 1)], Constant::one());
+        poly
+    }
+
+    fn add_term(&mut self, monomial: Monomial, c: Constant) {
+        match self.terms.entry(monomial) {
+            Entry::Vacant(entry) => {
+                if !c.is_zero() {
+                    entry.insert(c);
+                }
+            }
+            Entry::Occupied(mut entry) => {
+                *entry.get_mut() += c;
+                if entry.get().is_zero() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut sum = self.clone();
+        for (monomial, c) in &other.terms {
+            sum.add_term(monomial.clone(), c.clone());
+        }
+        sum
+    }
+
+    fn neg(&self) -> Self {
+        Self {
+            terms: self
+                .terms
+                .iter()
+                .map(|(monomial, c)| (monomial.clone(), -c))
+                .collect(),
+        }
+    }
+
+    // `None` as soon as the product has more than `MAX_POLY_TERMS` terms
+    fn mul(&self, other: &Self) -> Option<Self> {
+        let mut product = Self::default();
+        for (a, ca) in &self.terms {
+            for (b, cb) in &other.terms {
+                product.add_term(mul_monomials(a, b), ca * cb);
+            }
+            if product.terms.len() > MAX_POLY_TERMS {
+                return None;
+            }
+        }
+        Some(product)
+    }
+
+    // `None` as soon as a partial power has more than `MAX_POLY_TERMS` terms
+    fn pow(&self, n: u32) -> Option<Self> {
+        (0..n).try_fold(Self::constant(Constant::one()), |acc, _| acc.mul(self))
+    }
+}
+
+// the signature and number of arguments of a polynomial operator
+fn poly_signature(node: &Math) -> Option<(Id, usize)> {
+    match node {
+        Math::Add([sig, _, _])
+        | Math::Sub([sig, _, _])
+        | Math::Mul([sig, _, _])
+        | Math::Pow([sig, _, _]) => Some((*sig, 2)),
+        Math::Neg([sig, _]) => Some((*sig, 1)),
+        _ => None,
+    }
+}
+
+// Polynomials of the classes of one egraph, meant to be built once
+// and shared by every match of an iteration.
+pub struct PolyBuilder<'a> {
+    egraph: &'a EGraph,
+    best: DeterministicExtractor<'a, AltCost<'a>>,
+    vars: HashMap<Id, PolyVar>,
+    memo: HashMap<(Id, Symbol), Poly>,
+}
+
+impl<'a> PolyBuilder<'a> {
+    pub fn new(egraph: &'a EGraph) -> Self {
+        Self {
+            egraph,
+            best: DeterministicExtractor::new(egraph, AltCost::new(egraph)),
+            vars: Default::default(),
+            memo: Default::default(),
+        }
+    }
+
+    fn var(&mut self, id: Id) -> PolyVar {
+        let id = self.egraph.find(id);
+        let best = &self.best;
+        self.vars
+            .entry(id)
+            .or_insert_with(|| PolyVar {
+                term: best.find_best(id).1.to_string(),
+                id,
+            })
+            .clone()
+    }
+
+    // the representation of the polynomial operators of a class, the
+    // first by name if there are several, `None` if there are none
+    fn class_repr(&self, id: Id) -> Option<Symbol> {
+        self.egraph[id]
+            .nodes
+            .iter()
+            .filter_map(|node| {
+                let (sig, arity) = poly_signature(node)?;
+                homogeneous_repr(self.egraph, sig, arity)
+            })
+            .min_by(|a, b| a.as_str().cmp(b.as_str()))
+    }
+
+    // the polynomial of a class and its representation,
+    // or `None` if the class is opaque
+    pub fn class_poly(&mut self, id: Id) -> Option<(Symbol, Poly)> {
+        let id = self.egraph.find(id);
+        let repr = self.class_repr(id)?;
+        let poly = self.repr_poly(id, repr);
+        (poly != Poly::var(self.var(id))).then_some((repr, poly))
+    }
+
+    fn repr_poly(&mut self, id: Id, repr: Symbol) -> Poly {
+        let egraph = self.egraph;
+        let id = egraph.find(id);
+        if let Some(poly) = self.memo.get(&(id, repr)) {
+            return poly.clone();
+        }
+        if let Some((c, _)) = &egraph[id].data {
+            return Poly::constant(c.clone());
+        }
+
+        let best_cost = self.best.find_best_cost(id);
+        let mut nodes: Vec<&Math> = egraph[id]
+            .nodes
+            .iter()
+            // the first child of an operator is its signature, not an argument
+            .filter(|node| {
+                node.children()
+                    .iter()
+                    .skip(1)
+                    .all(|&arg| self.best.find_best_cost(arg) < best_cost)
+            })
+            .collect();
+        nodes.sort_by(|x, y| self.best.compare_nodes(x, y));
+
+        let poly = nodes
+            .into_iter()
+            .find_map(|node| self.node_poly(node, repr))
+            .unwrap_or_else(|| Poly::var(self.var(id)));
+        self.memo.insert((id, repr), poly.clone());
+        poly
+    }
+
+    // `None` if the node is not a polynomial operator over `repr` or grows too large
+    fn node_poly(&mut self, node: &Math, repr: Symbol) -> Option<Poly> {
+        let (sig, arity) = poly_signature(node)?;
+        if homogeneous_repr(self.egraph, sig, arity) != Some(repr) {
+            return None;
+        }
+
+        let args = &node.children()[1..];
+        let poly = match node {
+            Math::Add(_) => self
+                .repr_poly(args[0], repr)
+                .add(&self.repr_poly(args[1], repr)),
+            Math::Sub(_) => self
+                .repr_poly(args[0], repr)
+                .add(&self.repr_poly(args[1], repr).neg()),
+            Math::Mul(_) => self
+                .repr_poly(args[0], repr)
+                .mul(&self.repr_poly(args[1], repr))?,
+            Math::Neg(_) => self.repr_poly(args[0], repr).neg(),
+            Math::Pow(_) => {
+                let (n, _) = self.egraph[args[1]].data.as_ref()?;
+                if !n.is_integer() {
+                    return None;
+                }
+                let n: u32 = n.to_integer().try_into().ok()?;
+                if !(1..=MAX_POLY_EXPONENT).contains(&n) {
+                    return None;
+                }
+                self.repr_poly(args[0], repr).pow(n)?
+            }
+            _ => unreachable!(),
+        };
+
+        (poly.terms.len() <= MAX_POLY_TERMS).then_some(poly)
+    }
+}
+
+// The expansion of `poly` as a left-nested sum of products, using the
+// binary signature of `repr` throughout. Each variable of `poly` becomes
+// a pattern variable, bound to its eclass in `subst`.
+fn poly_pattern(poly: &Poly, repr: Symbol, subst: &mut Subst) -> PatternAst<Math> {
+    let mut ast = PatternAst::default();
+    let t = ast.add(ENodeOrVar::ENode(Math::Symbol(repr)));
+    let sig = ast.add(ENodeOrVar::ENode(Math::Other(
+        "$Type".into(),
+        vec![t, t, t],
+    )));
+
+    let mut vars: HashMap<Id, Id> = HashMap::new();
+    let mut sum = None;
+    for (monomial, c) in &poly.terms {
+        let mut product = None;
+        for (PolyVar { id, .. }, n) in monomial {
+            let var = *vars.entry(*id).or_insert_with(|| {
+                let var: Var = format!("?poly{}", id).parse().unwrap();
+                subst.insert(var, *id);
+                ast.add(ENodeOrVar::Var(var))
+            });
+            for _ in 0..*n {
+                product = Some(match product {
+                    None => var,
+                    Some(acc) => ast.add(ENodeOrVar::ENode(Math::Mul([sig, acc, var]))),
+                });
+            }
+        }
+
+        let term = match product {
+            None => ast.add(ENodeOrVar::ENode(Math::Constant(c.clone()))),
+            Some(product) if c.is_one() => product,
+            Some(product) => {
+                let c = ast.add(ENodeOrVar::ENode(Math::Constant(c.clone())));
+                ast.add(ENodeOrVar::ENode(Math::Mul([sig, c, product])))
+            }
+        };
+
+        sum = Some(match sum {
+            None => term,
+            Some(acc) => ast.add(ENodeOrVar::ENode(Math::Add([sig, acc, term]))),
+        });
+    }
+
+    if sum.is_none() {
+        ast.add(ENodeOrVar::ENode(Math::Constant(Constant::zero())));
+    }
+    ast
+}
+
+// the polynomial of an eclass, or `None` if the class is opaque
+pub fn class_poly(egraph: &EGraph, id: Id) -> Option<Poly> {
+    PolyBuilder::new(egraph)
+        .class_poly(id)
+        .map(|(_, poly)| poly)
+}
+
+// The expansion of the polynomial of `eclass` as a pattern, with `subst`
+// extended to bind its variables, or `None` if the class is opaque.
+pub fn poly_expansion(
+    builder: &mut PolyBuilder,
+    eclass: Id,
+    subst: &Subst,
+) -> Option<(PatternAst<Math>, Subst)> {
+    let (repr, poly) = builder.class_poly(eclass)?;
+    let mut subst = subst.clone();
+    let expansion = poly_pattern(&poly, repr, &mut subst);
+    Some((expansion, subst))
+}
+
+// Unions an eclass with an expansion from `poly_expansion`, justified by
+// `rule_name` in proofs. Like other appliers it unions the matched term
+// itself when `searcher_ast` is given. Returns whether the egraph changed.
+pub fn union_expansion(
+    egraph: &mut EGraph,
+    eclass: Id,
+    (expansion, subst): &(PatternAst<Math>, Subst),
+    searcher_ast: Option<&PatternAst<Math>>,
+    rule_name: Symbol,
+) -> bool {
+    match searcher_ast {
+        Some(ast) => {
+            egraph
+                .union_instantiations(ast, expansion, subst, rule_name)
+                .1
+        }
+        None => {
+            let id = egraph.add_instantiation(expansion, subst);
+            egraph.union_trusted(eclass, id, rule_name)
+        }
+    }
+}
+
+// Normalizes every match at once, sharing the polynomials of one snapshot
+// of the egraph, and returns the classes that changed.
+pub fn normalize_polynomials(
+    egraph: &mut EGraph,
+    matches: &[SearchMatches<Math>],
+    rule_name: Symbol,
+) -> Vec<Id> {
+    let with_ast = egraph.are_explanations_enabled();
+    let mut builder = PolyBuilder::new(egraph);
+    let expansions: Vec<_> = matches
+        .iter()
+        .flat_map(|m| m.substs.iter().map(move |subst| (m, subst)))
+        .filter_map(|(m, subst)| Some((m, poly_expansion(&mut builder, m.eclass, subst)?)))
+        .collect();
+
+    let mut changed = vec![];
+    for (m, expansion) in &expansions {
+        let ast = m.ast.as_ref().filter(|_| with_ast).map(|ast| ast.as_ref());
+        if union_expansion(egraph, m.eclass, expansion, ast, rule_name) {
+            changed.push(m.eclass);
+        }
+    }
+    changed
+}
+
+// Like `normalize_polynomials` for a single match.
+pub fn normalize_polynomial(
+    egraph: &mut EGraph,
+    eclass: Id,
+    subst: &Subst,
+    searcher_ast: Option<&PatternAst<Math>>,
+    rule_name: Symbol,
+) -> bool {
+    let expansion = poly_expansion(&mut PolyBuilder::new(egraph), eclass, subst);
+    match expansion {
+        Some(expansion) => union_expansion(egraph, eclass, &expansion, searcher_ast, rule_name),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIG: &str = "($Type binary64 binary64 binary64)";
+
+    fn add(egraph: &mut EGraph, expr: &str) -> Id {
+        let id = egraph.add_expr(&expr.replace("SIG", SIG).parse().unwrap());
+        egraph.rebuild();
+        id
+    }
+
+    fn vars(egraph: &mut EGraph, names: &[&str]) -> Vec<Poly> {
+        let ids: Vec<Id> = names.iter().map(|name| add(egraph, name)).collect();
+        let mut builder = PolyBuilder::new(egraph);
+        ids.into_iter()
+            .map(|id| Poly::var(builder.var(id)))
+            .collect()
+    }
+
+    // the terms of `poly` with each variable named by its best term
+    fn shape(poly: &Poly) -> Vec<(Vec<(String, u32)>, String)> {
+        poly.terms
+            .iter()
+            .map(|(monomial, c)| {
+                let vars = monomial
+                    .iter()
+                    .map(|(var, n)| (var.term.clone(), *n))
+                    .collect();
+                (vars, c.to_string())
+            })
+            .collect()
+    }
+
+    fn constant(n: i64) -> Poly {
+        Poly::constant(Constant::from_integer(n.into()))
+    }
+
+    #[test]
+    fn expands_products_and_powers() {
+        let mut egraph = EGraph::default();
+        let id = add(&mut egraph, "(pow SIG (- SIG x y) 2)");
+        let [x, y] = <[Poly; 2]>::try_from(vars(&mut egraph, &["x", "y"])).unwrap();
+
+        // x^2 - 2xy + y^2
+        let xy = x.mul(&y).unwrap();
+        let expected = x
+            .mul(&x)
+            .unwrap()
+            .add(&constant(-2).mul(&xy).unwrap())
+            .add(&y.mul(&y).unwrap());
+        assert_eq!(class_poly(&egraph, id), Some(expected));
+    }
+
+    #[test]
+    fn combines_like_terms() {
+        let mut egraph = EGraph::default();
+        let id = add(&mut egraph, "(- SIG (+ SIG (* SIG 3 x) x) (* SIG x 4))");
+        assert_eq!(class_poly(&egraph, id), Some(Poly::default()));
+
+        let id = add(&mut egraph, "(+ SIG (* SIG x y) (* SIG y x))");
+        let [x, y] = <[Poly; 2]>::try_from(vars(&mut egraph, &["x", "y"])).unwrap();
+        let expected = constant(2).mul(&x.mul(&y).unwrap()).unwrap();
+        assert_eq!(class_poly(&egraph, id), Some(expected));
+    }
+
+    #[test]
+    fn stops_at_the_term_limit() {
+        let names: Vec<String> = (0..9).map(|i| format!("x{}", i)).collect();
+        let sum = names
+            .iter()
+            .skip(1)
+            .fold(names[0].clone(), |acc, x| format!("(+ SIG {} {})", acc, x));
+
+        let mut egraph = EGraph::default();
+        let sum_id = add(&mut egraph, &sum);
+        let poly = class_poly(&egraph, sum_id).unwrap();
+        // 45 terms fit, 165 do not
+        assert_eq!(poly.pow(2).map(|p| p.terms.len()), Some(45));
+        assert_eq!(poly.pow(3), None);
+
+        let square = add(&mut egraph, &format!("(pow SIG {} 2)", sum));
+        assert_eq!(class_poly(&egraph, square).map(|p| p.terms.len()), Some(45));
+        let cube = add(&mut egraph, &format!("(pow SIG {} 3)", sum));
+        assert_eq!(class_poly(&egraph, cube), None);
+    }
+
+    #[test]
+    fn normalizes_with_explanations() {
+        let rules = mk_rules(&[("poly", "(* ?s ?a ?b)", "#:applier poly-normalize")]).unwrap();
+        let expr = format!("(* {} (+ {} x 1) (- {} x 1))", SIG, SIG, SIG);
+        let expanded = format!("(+ {} -1 (* {} x x))", SIG, SIG);
+
+        let mut context = crate::Context::new();
+        context.add_expr(&expr.parse().unwrap());
+        context.run(&rules, 2, 1000, false);
+
+        let proof = context.get_proof(&expr.parse().unwrap(), &expanded.parse().unwrap());
+        assert!(proof.contains("poly"), "{}", proof);
+    }
+
+    #[test]
+    fn normal_forms_do_not_depend_on_class_ids() {
+        let expr = "(* SIG (+ SIG a 1) (- SIG b c))";
+        let shapes: Vec<_> = [["a", "b", "c"], ["c", "b", "a"]]
+            .iter()
+            .map(|names| {
+                let mut egraph = EGraph::default();
+                vars(&mut egraph, names);
+                let id = add(&mut egraph, expr);
+                shape(&class_poly(&egraph, id).unwrap())
+            })
+            .collect();
+        assert_eq!(shapes[0], shapes[1]);
+        let vars: Vec<&str> = shapes[0]
+            .iter()
+            .flat_map(|(monomial, _)| monomial.iter().map(|(v, _)| v.as_str()))
+            .collect();
+        assert_eq!(vars, ["a", "b", "a", "c", "b", "c"]);
+    }
+
+    #[test]
+    fn classes_on_a_cycle_get_one_normal_form() {
+        let mut egraph = EGraph::default();
+        let inc = add(&mut egraph, "(+ SIG x 1)");
+        // `(- SIG inc 0)` puts the class inside itself
+        let same = add(&mut egraph, "(- SIG (+ SIG x 1) 0)");
+        egraph.union(inc, same);
+        egraph.rebuild();
+        let square = add(&mut egraph, "(* SIG (+ SIG x 1) (+ SIG x 1))");
+
+        let alone = PolyBuilder::new(&egraph).class_poly(inc);
+        let mut builder = PolyBuilder::new(&egraph);
+        builder.class_poly(square).unwrap();
+        assert_eq!(builder.class_poly(inc), alone);
+
+        let [x] = <[Poly; 1]>::try_from(vars(&mut egraph, &["x"])).unwrap();
+        assert_eq!(alone.map(|(_, poly)| poly), Some(x.add(&constant(1))));
+    }
+}
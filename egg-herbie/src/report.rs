@@ -0,0 +1,212 @@
+use egg::{StopReason, Symbol};
+use indexmap::IndexMap;
+use std::cell::OnceCell;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::math::*;
+use crate::{find_extracted, CALLBACK_STOP};
+
+// limits a phase of a run was configured with,
+// since egg does not expose them after the fact
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    // the phase recorded with the iterations run under these limits
+    pub phase: usize,
+    pub iter_limit: usize,
+    pub node_limit: usize,
+}
+
+pub fn stop_reason_name(stop_reason: &Option<StopReason>) -> Option<&'static str> {
+    stop_reason.as_ref().map(|stop_reason| match stop_reason {
+        StopReason::Saturated => "saturated",
+        StopReason::IterationLimit(_) => "iter limit",
+        StopReason::NodeLimit(_) => "node limit",
+        StopReason::TimeLimit(_) => "time limit",
+        StopReason::Other(msg) if msg == CALLBACK_STOP => "callback",
+        StopReason::Other(_) => "other",
+    })
+}
+
+fn stop_reason_message(stop_reason: &Option<StopReason>) -> Option<&str> {
+    match stop_reason {
+        Some(StopReason::Other(msg)) => Some(msg),
+        _ => None,
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_f64(x: f64) -> String {
+    if x.is_finite() {
+        format!("{}", x)
+    } else {
+        "null".into()
+    }
+}
+
+fn json_option_string(s: Option<&str>) -> String {
+    s.map(json_string).unwrap_or_else(|| "null".into())
+}
+
+fn json_object<'a>(entries: impl IntoIterator<Item = (&'a str, String)>) -> String {
+    let fields: Vec<String> = entries
+        .into_iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+fn json_rule_counts<'a>(applied: impl IntoIterator<Item = (&'a Symbol, &'a usize)>) -> String {
+    json_object(
+        applied
+            .into_iter()
+            .map(|(name, count)| (name.as_str(), count.to_string())),
+    )
+}
+
+fn json_iteration(iteration: &Iteration) -> String {
+    json_object([
+        ("phase", iteration.data.phase.to_string()),
+        ("nodes", iteration.egraph_nodes.to_string()),
+        ("classes", iteration.egraph_classes.to_string()),
+        ("time", json_f64(iteration.total_time)),
+        ("hook_time", json_f64(iteration.hook_time)),
+        ("search_time", json_f64(iteration.search_time)),
+        ("apply_time", json_f64(iteration.apply_time)),
+        ("rebuild_time", json_f64(iteration.rebuild_time)),
+        ("rebuilds", iteration.n_rebuilds.to_string()),
+        (
+            "stop_reason",
+            json_option_string(stop_reason_name(&iteration.stop_reason)),
+        ),
+        ("applied", json_rule_counts(iteration.applied.iter())),
+        (
+            "costs",
+            json_array(
+                iteration
+                    .data
+                    .extracted
+                    .iter()
+                    .map(|(_, ext)| ext.cost.to_string()),
+            ),
+        ),
+    ])
+}
+
+// best extraction of each root, as `find_extracted` finds it,
+// with a null cost and term if there is no sound extraction
+fn json_roots(runner: &Runner) -> String {
+    let fallback = OnceCell::new();
+    let extracted: Vec<Option<(usize, RecExpr)>> = runner
+        .roots
+        .iter()
+        .map(|&root| {
+            find_extracted(runner, &fallback, root, usize::MAX)
+                .map(|ext| (ext.cost, ext.best.clone()))
+        })
+        .collect();
+
+    json_array(
+        runner
+            .roots
+            .iter()
+            .zip(extracted)
+            .map(|(&root, extracted)| {
+                let (cost, best) = match extracted {
+                    Some((cost, best)) => (cost.to_string(), json_string(&best.to_string())),
+                    None => ("null".into(), "null".into()),
+                };
+                json_object([
+                    ("id", usize::from(root).to_string()),
+                    ("cost", cost),
+                    ("best", best),
+                ])
+            }),
+    )
+}
+
+fn json_limits(limits: &Limits) -> String {
+    json_object([
+        ("phase", limits.phase.to_string()),
+        ("iter_limit", limits.iter_limit.to_string()),
+        ("node_limit", limits.node_limit.to_string()),
+    ])
+}
+
+// `limits` holds the limits of each phase that ran, in order
+pub fn make_report(
+    runner: &Runner,
+    limits: &[Limits],
+    extract_every: usize,
+    fp_exact: bool,
+) -> String {
+    let mut rule_counts: IndexMap<Symbol, usize> = Default::default();
+    for iteration in &runner.iterations {
+        for (name, count) in iteration.applied.iter() {
+            *rule_counts.entry(*name).or_default() += count;
+        }
+    }
+
+    json_object([
+        (
+            "limits",
+            json_object([
+                ("phases", json_array(limits.iter().map(json_limits))),
+                (
+                    "constant_fold",
+                    runner.egraph.analysis.constant_fold.to_string(),
+                ),
+                ("fp_exact", fp_exact.to_string()),
+                ("extract_every", extract_every.to_string()),
+                (
+                    "prune",
+                    json_string(&runner.egraph.analysis.prune.to_string()),
+                ),
+            ]),
+        ),
+        (
+            "stop_reason",
+            json_option_string(stop_reason_name(&runner.stop_reason)),
+        ),
+        (
+            "stop_message",
+            json_option_string(stop_reason_message(&runner.stop_reason)),
+        ),
+        (
+            "iterations",
+            json_array(runner.iterations.iter().map(json_iteration)),
+        ),
+        ("rules", json_rule_counts(rule_counts.iter())),
+        (
+            "unsound",
+            runner
+                .egraph
+                .analysis
+                .unsound
+                .load(Ordering::SeqCst)
+                .to_string(),
+        ),
+        ("roots", json_roots(runner)),
+    ])
+}
@@ -0,0 +1,330 @@
+// Textual rule files.
+//
+// Each non-empty line describes one rule:
+//
+//   <name> <lhs> <rhs> [#:groups (<group> ...)] [#:when <condition>]
+//
+// where `<lhs>` and `<rhs>` are patterns in the same syntax `mk_rule`
+// accepts, except that a multi-pattern side is wrapped in parentheses,
+// as in `(?a = <pattern>, ?b = <pattern>)`, and `<rhs>` may instead be
+// `#:applier <name>`. Groups are arbitrary names like `simplify`,
+// `fp-safe` or `sound`, and `<condition>` is one of
+//
+//   (= <pattern> <pattern>)   both sides already exist and are equivalent
+//   (constant ?x)             `?x` has a known constant value
+//   (nonzero ?x)              `?x` has a known, non-zero constant value
+//
+// A `;` outside any parentheses starts a comment that runs to the end of the line.
+// Rule names must be unique within a file.
+
+use egg::Var;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::math::*;
+use crate::sexp::split_sexps;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleDef {
+    pub name: String,
+    pub lhs: String,
+    pub rhs: String,
+    pub groups: Vec<String>,
+    pub condition: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RuleParseError {}
+
+impl fmt::Display for RuleDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.name, self.lhs, self.rhs)?;
+        if !self.groups.is_empty() {
+            write!(f, " #:groups ({})", self.groups.join(" "))?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " #:when {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+// strips one level of parentheses, failing on atoms
+fn list_items(sexp: &str) -> Option<Vec<&str>> {
+    let inner = sexp.strip_prefix('(')?.strip_suffix(')')?;
+    split_sexps(inner).ok()
+}
+
+fn parse_pattern(pattern: &str, what: &str) -> Result<Pattern, String> {
+    Pattern::from_str(pattern).map_err(|e| format!("bad {} `{}`: {}", what, pattern, e))
+}
+
+// the bindings of a parenthesized multi-pattern, anything else as-is
+fn unwrap_multi_pattern(side: &str) -> &str {
+    match list_items(side).as_deref() {
+        Some([var, "=", ..]) if var.starts_with('?') => &side[1..side.len() - 1],
+        _ => side,
+    }
+}
+
+fn parse_var(var: &str) -> Result<Var, String> {
+    var.parse()
+        .map_err(|_| format!("expected a pattern variable, got `{}`", var))
+}
+
+fn parse_condition(condition: &str) -> Result<RuleCondition, String> {
+    let items = list_items(condition)
+        .ok_or_else(|| format!("expected a condition, got `{}`", condition))?;
+    match items.as_slice() {
+        ["=", a, b] => Ok(RuleCondition::Equal(
+            parse_pattern(a, "condition pattern")?,
+            parse_pattern(b, "condition pattern")?,
+        )),
+        ["constant", v] => Ok(RuleCondition::Constant(parse_var(v)?)),
+        ["nonzero", v] => Ok(RuleCondition::NonZero(parse_var(v)?)),
+        _ => Err(format!("unknown condition `{}`", condition)),
+    }
+}
+
+impl RuleDef {
+    pub fn in_any_group(&self, groups: &[&str]) -> bool {
+        self.groups.iter().any(|g| groups.contains(&g.as_str()))
+    }
+
+    pub fn to_rewrite(&self) -> Result<Rewrite, String> {
+        let condition = self.condition.as_deref().map(parse_condition).transpose()?;
+        mk_conditional_rule(
+            &self.name,
+            unwrap_multi_pattern(&self.lhs),
+            unwrap_multi_pattern(&self.rhs),
+            condition,
+        )
+    }
+}
+
+fn parse_rule_line(line: &str) -> Result<Option<RuleDef>, String> {
+    let items = split_sexps(line)?;
+    let (name, lhs, rhs, mut options) = match items.as_slice() {
+        [] => return Ok(None),
+        [name, lhs, "#:applier", applier, options @ ..] => {
+            (name, lhs, format!("#:applier {}", applier), options)
+        }
+        [name, lhs, rhs, options @ ..] => (name, lhs, rhs.to_string(), options),
+        _ => return Err("expected `<name> <lhs> <rhs>`".into()),
+    };
+
+    if name.starts_with('(') {
+        return Err(format!("expected a rule name, got `{}`", name));
+    }
+
+    let mut rule = RuleDef {
+        name: name.to_string(),
+        lhs: lhs.to_string(),
+        rhs,
+        groups: vec![],
+        condition: None,
+    };
+
+    while let [keyword, value, rest @ ..] = options {
+        match *keyword {
+            "#:groups" => {
+                let groups = list_items(value)
+                    .ok_or_else(|| format!("expected a list of groups, got `{}`", value))?;
+                rule.groups.extend(groups.into_iter().map(String::from));
+            }
+            "#:when" => {
+                if rule.condition.is_some() {
+                    return Err("more than one `#:when` condition".into());
+                }
+                rule.condition = Some(value.to_string());
+            }
+            _ => return Err(format!("unknown option `{}`", keyword)),
+        }
+        options = rest;
+    }
+
+    if let [extra] = options {
+        return Err(format!("`{}` is missing a value", extra));
+    }
+
+    Ok(Some(rule))
+}
+
+fn parse_rule_lines(text: &str) -> Result<Vec<(usize, RuleDef)>, RuleParseError> {
+    let mut rules = vec![];
+    // egg's runner panics on rules sharing a name, so reject them here
+    let mut first_lines: HashMap<String, usize> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        match parse_rule_line(line) {
+            Ok(Some(rule)) => {
+                if let Some(first) = first_lines.insert(rule.name.clone(), i + 1) {
+                    return Err(RuleParseError {
+                        line: i + 1,
+                        message: format!(
+                            "duplicate rule name `{}`, first defined on line {}",
+                            rule.name, first
+                        ),
+                    });
+                }
+                rules.push((i + 1, rule))
+            }
+            Ok(None) => (),
+            Err(message) => {
+                return Err(RuleParseError {
+                    line: i + 1,
+                    message,
+                })
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+// Parses a rule file, checking that every pattern and condition is valid.
+pub fn parse_rule_file(text: &str) -> Result<Vec<RuleDef>, RuleParseError> {
+    parse_rule_lines(text)?
+        .into_iter()
+        .map(|(line, rule)| match rule.to_rewrite() {
+            Ok(_) => Ok(rule),
+            Err(message) => Err(RuleParseError { line, message }),
+        })
+        .collect()
+}
+
+// Loads every rule in the file that belongs to one of `groups`,
+// or every rule if `groups` is empty.
+pub fn load_rules(text: &str, groups: &[&str]) -> Result<Vec<Rewrite>, RuleParseError> {
+    parse_rule_lines(text)?
+        .into_iter()
+        .filter(|(_, rule)| groups.is_empty() || rule.in_any_group(groups))
+        .map(|(line, rule)| {
+            rule.to_rewrite()
+                .map_err(|message| RuleParseError { line, message })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADD: &str = "(+ ($Type binary64 binary64 binary64) ?a ?b)";
+    const ADD_REV: &str = "(+ ($Type binary64 binary64 binary64) ?b ?a)";
+
+    fn parse_error(text: &str) -> RuleParseError {
+        parse_rule_file(text).expect_err("expected a parse error")
+    }
+
+    #[test]
+    fn parses_groups_and_conditions() {
+        let text = format!(
+            "; commutativity\n\n+-commutes {} {} #:groups (simplify sound) #:when (nonzero ?a)\n",
+            ADD, ADD_REV
+        );
+        let rules = parse_rule_file(&text).unwrap();
+        assert_eq!(
+            rules,
+            vec![RuleDef {
+                name: "+-commutes".into(),
+                lhs: ADD.into(),
+                rhs: ADD_REV.into(),
+                groups: vec!["simplify".into(), "sound".into()],
+                condition: Some("(nonzero ?a)".into()),
+            }]
+        );
+        // printing a rule gives back its line
+        assert_eq!(parse_rule_file(&rules[0].to_string()).unwrap(), rules);
+    }
+
+    #[test]
+    fn groups_accumulate_and_filter_loading() {
+        let text = format!(
+            "a {add} {rev} #:groups (simplify) #:groups (sound)\nb {rev} {add} #:groups (fp-safe)\nc {add} {add}\n",
+            add = ADD,
+            rev = ADD_REV
+        );
+        let rules = parse_rule_file(&text).unwrap();
+        assert_eq!(rules[0].groups, vec!["simplify", "sound"]);
+        assert!(rules[2].groups.is_empty());
+
+        let names = |groups: &[&str]| -> Vec<String> {
+            load_rules(&text, groups)
+                .unwrap()
+                .iter()
+                .map(|rule| rule.name.to_string())
+                .collect()
+        };
+        assert_eq!(names(&["sound"]), vec!["a"]);
+        assert_eq!(names(&["fp-safe", "simplify"]), vec!["a", "b"]);
+        assert_eq!(names(&[]), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn parses_every_condition() {
+        for condition in ["(= ?a ?b)", "(constant ?a)", "(nonzero ?b)"] {
+            let text = format!("r {} {} #:when {}", ADD, ADD_REV, condition);
+            let rules = parse_rule_file(&text).unwrap();
+            assert_eq!(rules[0].condition.as_deref(), Some(condition));
+        }
+    }
+
+    #[test]
+    fn parses_multi_patterns_and_named_appliers() {
+        let text = "\
+            swap-pair (?a = (+ ?s ?x ?y), ?b = (- ?s ?x ?y)) (?a = (+ ?s ?y ?x), ?b = (+ ?s ?x (neg ?s ?y))) #:groups (simplify)
+            expand (pow ?s ?a ?b) #:applier pow-expand #:when (constant ?b)
+        ";
+        let rules = parse_rule_file(text).unwrap();
+        assert_eq!(rules[0].lhs, "(?a = (+ ?s ?x ?y), ?b = (- ?s ?x ?y))");
+        assert_eq!(rules[0].groups, vec!["simplify"]);
+        assert_eq!(rules[1].rhs, "#:applier pow-expand");
+        assert_eq!(rules[1].condition.as_deref(), Some("(constant ?b)"));
+        assert_eq!(load_rules(text, &[]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let cases = [
+            format!("r {}", ADD),
+            format!("{} {} {}", ADD, ADD, ADD_REV),
+            format!("r {} {} #:groups simplify", ADD, ADD_REV),
+            format!("r {} {} #:when", ADD, ADD_REV),
+            format!("r {} {} #:unknown (x)", ADD, ADD_REV),
+            format!("r {} {} #:when (positive ?a)", ADD, ADD_REV),
+            format!("r {} {} #:when (constant a)", ADD, ADD_REV),
+            format!(
+                "r {} {} #:when (nonzero ?a) #:when (nonzero ?b)",
+                ADD, ADD_REV
+            ),
+            format!("r {} (+ ?a", ADD),
+            format!("r {} #:applier no-such-applier", ADD),
+            format!("r (?a = {}) {}", ADD, ADD_REV),
+        ];
+        for case in cases {
+            let text = format!("; header\n{}", case);
+            assert_eq!(parse_error(&text).line, 2, "{}", case);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let text = format!("r {add} {rev}\n\nr {rev} {add}\n", add = ADD, rev = ADD_REV);
+        let error = parse_error(&text);
+        assert_eq!(error.line, 3);
+        assert!(error.message.contains("`r`"), "{}", error);
+        assert!(error.message.contains("line 1"), "{}", error);
+    }
+}
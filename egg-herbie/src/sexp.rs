@@ -0,0 +1,246 @@
+use egg::{Id, Language};
+use std::collections::HashMap;
+
+use crate::math::*;
+
+// Splits egg IR text into its top-level s-expressions.
+// Atoms are returned as-is and `;` starts a comment that runs to the end of the line.
+// Comments are only allowed between top-level s-expressions, since the returned
+// slices would keep a comment inside one and the expression parser cannot skip it.
+pub fn split_sexps(text: &str) -> Result<Vec<&str>, String> {
+    let mut items = vec![];
+    let mut depth = 0usize;
+    let mut start = None;
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                if depth > 0 {
+                    return Err(format!(
+                        "line {}: comments are only allowed between expressions",
+                        line
+                    ));
+                }
+                // a comment ends any atom before it
+                if let Some(s) = start.take() {
+                    items.push(&text[s..i]);
+                }
+                while let Some(&(_, c)) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' => {
+                if depth == 0 {
+                    if let Some(s) = start.take() {
+                        items.push(&text[s..i]);
+                    }
+                    start = Some(i);
+                    start_line = line;
+                }
+                depth += 1;
+            }
+            ')' => {
+                if depth == 0 {
+                    return Err(format!("line {}: unexpected `)`", line));
+                }
+                depth -= 1;
+                if depth == 0 {
+                    items.push(&text[start.take().unwrap()..i + 1]);
+                }
+            }
+            c if c.is_whitespace() => {
+                if c == '\n' {
+                    line += 1;
+                }
+                if depth == 0 {
+                    if let Some(s) = start.take() {
+                        items.push(&text[s..i]);
+                    }
+                }
+            }
+            _ => {
+                if start.is_none() {
+                    start = Some(i);
+                    start_line = line;
+                }
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(format!("line {}: unclosed `(`", start_line));
+    }
+    if let Some(s) = start {
+        items.push(&text[s..]);
+    }
+
+    Ok(items)
+}
+
+// Prints `expr` with every compound subterm that occurs more than once bound
+// by `(let (<var> <term>) <body>)` and referred to by its `$v<n>` variable,
+// the form `flatten-let` in Herbie reads. Terms may refer to earlier variables.
+pub fn to_let_sexp(expr: &RecExpr) -> String {
+    // merge identical subterms, children still come before their parents
+    let mut node_ids: HashMap<Math, Id> = HashMap::new();
+    let mut nodes: Vec<Math> = vec![];
+    let mut new_ids: Vec<Id> = vec![];
+    for node in expr.as_ref() {
+        let node = node
+            .clone()
+            .map_children(|child| new_ids[usize::from(child)]);
+        let id = *node_ids.entry(node.clone()).or_insert_with(|| {
+            nodes.push(node);
+            Id::from(nodes.len() - 1)
+        });
+        new_ids.push(id);
+    }
+    let root = match new_ids.last() {
+        Some(&root) => usize::from(root),
+        None => return String::new(),
+    };
+
+    let mut uses = vec![0usize; nodes.len()];
+    for node in &nodes {
+        for &child in node.children() {
+            uses[usize::from(child)] += 1;
+        }
+    }
+
+    // how each node is referred to, its variable if bound and its term otherwise
+    let mut refs: Vec<String> = Vec::with_capacity(nodes.len());
+    let mut bindings = vec![];
+    for (i, node) in nodes.iter().enumerate() {
+        let term = if node.is_leaf() {
+            node.to_string()
+        } else {
+            let mut term = format!("({}", node);
+            for &child in node.children() {
+                term.push(' ');
+                term.push_str(&refs[usize::from(child)]);
+            }
+            term.push(')');
+            term
+        };
+
+        if uses[i] > 1 && !node.is_leaf() && i != root {
+            let var = format!("$v{}", bindings.len());
+            bindings.push((var.clone(), term));
+            refs.push(var);
+        } else {
+            refs.push(term);
+        }
+    }
+
+    bindings
+        .into_iter()
+        .rev()
+        .fold(refs.swap_remove(root), |body, (var, term)| {
+            format!("(let ({} {}) {})", var, term, body)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Sexp {
+        Atom(String),
+        List(Vec<Sexp>),
+    }
+
+    // reads one s-expression from `tokens`, which are atoms and single parens
+    fn read(tokens: &mut std::iter::Peekable<std::vec::IntoIter<String>>) -> Sexp {
+        let token = tokens.next().expect("unexpected end of input");
+        if token != "(" {
+            return Sexp::Atom(token);
+        }
+        let mut items = vec![];
+        while tokens.peek().map(String::as_str) != Some(")") {
+            items.push(read(tokens));
+        }
+        tokens.next();
+        Sexp::List(items)
+    }
+
+    // substitutes every `let` binding into its body, like `flatten-let` in Herbie
+    fn flatten_let(sexp: &Sexp, env: &HashMap<String, String>) -> String {
+        match sexp {
+            Sexp::Atom(atom) => env.get(atom).unwrap_or(atom).clone(),
+            Sexp::List(items) => match &items[..] {
+                [Sexp::Atom(head), Sexp::List(binding), body] if head == "let" => {
+                    let [Sexp::Atom(var), term] = &binding[..] else {
+                        panic!("malformed let binding");
+                    };
+                    let mut env = env.clone();
+                    env.insert(var.clone(), flatten_let(term, &env));
+                    flatten_let(body, &env)
+                }
+                _ => {
+                    let items: Vec<String> = items.iter().map(|i| flatten_let(i, env)).collect();
+                    format!("({})", items.join(" "))
+                }
+            },
+        }
+    }
+
+    #[test]
+    fn comments_are_only_allowed_between_expressions() {
+        let text = "; header\n(neg ?s ?x) ; trailing\nx;tight\n";
+        assert_eq!(split_sexps(text).unwrap(), ["(neg ?s ?x)", "x"]);
+        let err = split_sexps("(neg ?s\n  ; inside\n  ?x)").unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+
+    // the let form of `expr`, checked to flatten back to `expr`
+    fn round_trip(expr: &str) -> String {
+        let expr: RecExpr = expr.parse().unwrap();
+        let let_sexp = to_let_sexp(&expr);
+        let tokens: Vec<String> = let_sexp
+            .replace('(', " ( ")
+            .replace(')', " ) ")
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        let flat = flatten_let(&read(&mut tokens.into_iter().peekable()), &HashMap::new());
+        assert_eq!(flat, expr.to_string(), "{}", let_sexp);
+        let_sexp
+    }
+
+    #[test]
+    fn terms_without_sharing_are_unchanged() {
+        let expr = "(+ ($Type binary64 binary64 binary64) x (neg ($Type binary64 binary64) y))";
+        assert_eq!(round_trip(expr), expr);
+    }
+
+    #[test]
+    fn nested_shared_subterms_are_bound_in_order() {
+        let neg = "(neg ($Type binary64 binary64) x)";
+        let add = format!("(+ ($Type binary64 binary64 binary64) {} {})", neg, neg);
+        let expr = format!("(* ($Type binary64 binary64 binary64) {} {})", add, add);
+        assert_eq!(
+            round_trip(&expr),
+            "(let ($v0 ($Type binary64 binary64 binary64)) \
+             (let ($v1 (neg ($Type binary64 binary64) x)) \
+             (let ($v2 (+ $v0 $v1 $v1)) \
+             (* $v0 $v2 $v2))))"
+        );
+    }
+
+    #[test]
+    fn subterms_shared_by_the_root_are_bound() {
+        let neg = "(neg ($Type binary64 binary64) x)";
+        let expr = format!("(* ($Type binary64 binary64 binary64) {} {})", neg, neg);
+        assert_eq!(
+            round_trip(&expr),
+            "(let ($v0 (neg ($Type binary64 binary64) x)) \
+             (* ($Type binary64 binary64 binary64) $v0 $v0))"
+        );
+    }
+}
@@ -0,0 +1,371 @@
+// Opt-in recording of FFI calls, so that a Herbie run can be replayed
+// without Racket by the `egg-herbie-replay` binary.
+//
+// Setting `EGG_HERBIE_TRACE=<path>` writes one line per `egraph_*` call
+// before it runs, and one line starting with `=` holding its result after,
+// so a trace cut short by a crash still ends at the call that crashed.
+// Fields are tab separated with `\\`, `\t`, `\n` and `\r` escaped,
+// and `\0` stands for a null string. A list is its length followed by its items.
+// Contexts and rulesets are numbered in the order they are created.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::sync::{Mutex, OnceLock};
+
+use crate::{Context, EGraphIter, FFIRule, FFIVariant, Ruleset};
+
+pub const TRACE_VAR: &str = "EGG_HERBIE_TRACE";
+
+const NULL_FIELD: &str = "\\0";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    Null,
+    Str(String),
+    List(Vec<String>),
+}
+
+impl From<&str> for Field {
+    fn from(s: &str) -> Self {
+        Field::Str(s.to_string())
+    }
+}
+
+impl From<String> for Field {
+    fn from(s: String) -> Self {
+        Field::Str(s)
+    }
+}
+
+impl From<u32> for Field {
+    fn from(n: u32) -> Self {
+        Field::Str(n.to_string())
+    }
+}
+
+impl From<u64> for Field {
+    fn from(n: u64) -> Self {
+        Field::Str(n.to_string())
+    }
+}
+
+impl From<bool> for Field {
+    fn from(b: bool) -> Self {
+        Field::Str(b.to_string())
+    }
+}
+
+impl From<Vec<String>> for Field {
+    fn from(items: Vec<String>) -> Self {
+        Field::List(items)
+    }
+}
+
+impl<T: Into<Field>> From<Option<T>> for Field {
+    fn from(field: Option<T>) -> Self {
+        field.map_or(Field::Null, Into::into)
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    if s == NULL_FIELD {
+        return None;
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
+
+impl Field {
+    pub fn encode(&self) -> String {
+        match self {
+            Field::Null => NULL_FIELD.to_string(),
+            Field::Str(s) => escape(s),
+            Field::List(items) => {
+                let mut fields = vec![items.len().to_string()];
+                fields.extend(items.iter().map(|item| escape(item)));
+                fields.join("\t")
+            }
+        }
+    }
+}
+
+pub fn encode_fields(fields: &[Field]) -> String {
+    let fields: Vec<String> = fields.iter().map(Field::encode).collect();
+    fields.join("\t")
+}
+
+// the fields of a trace line, `None` for a null string
+pub fn decode_line(line: &str) -> Vec<Option<String>> {
+    line.split('\t').map(unescape).collect()
+}
+
+#[derive(Default)]
+struct Tracer {
+    out: Option<File>,
+    contexts: HashMap<usize, usize>,
+    rulesets: HashMap<usize, usize>,
+    next_context: usize,
+    next_ruleset: usize,
+}
+
+impl Tracer {
+    fn write_line(&mut self, line: &str) {
+        if let Some(out) = &mut self.out {
+            if let Err(e) = writeln!(out, "{}", line) {
+                log::warn!("Could not write to the trace, stopping: {}", e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+fn tracer() -> Option<&'static Mutex<Tracer>> {
+    static TRACER: OnceLock<Option<Mutex<Tracer>>> = OnceLock::new();
+    TRACER
+        .get_or_init(|| {
+            let path = std::env::var_os(TRACE_VAR)?;
+            match File::create(&path) {
+                Ok(out) => Some(Mutex::new(Tracer {
+                    out: Some(out),
+                    ..Default::default()
+                })),
+                Err(e) => {
+                    log::warn!("Could not open trace {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+fn with_tracer<T>(f: impl FnOnce(&mut Tracer) -> T) -> Option<T> {
+    let mut tracer = tracer()?.lock().unwrap_or_else(|e| e.into_inner());
+    Some(f(&mut tracer))
+}
+
+pub fn is_enabled() -> bool {
+    tracer().is_some()
+}
+
+pub fn record_call(call: &str, args: Vec<Field>) {
+    let mut fields = vec![Field::from(call)];
+    fields.extend(args);
+    let line = encode_fields(&fields);
+    with_tracer(|tracer| tracer.write_line(&line));
+}
+
+pub fn record_result(result: Field) {
+    let line = encode_fields(&[Field::from("="), result]);
+    with_tracer(|tracer| tracer.write_line(&line));
+}
+
+pub fn register_context(ptr: *const Context) {
+    with_tracer(|tracer| {
+        tracer.contexts.insert(ptr as usize, tracer.next_context);
+        tracer.next_context += 1;
+    });
+}
+
+pub fn forget_context(ptr: *const Context) {
+    with_tracer(|tracer| tracer.contexts.remove(&(ptr as usize)));
+}
+
+pub fn register_ruleset(ptr: *const Ruleset) {
+    with_tracer(|tracer| {
+        tracer.rulesets.insert(ptr as usize, tracer.next_ruleset);
+        tracer.next_ruleset += 1;
+    });
+}
+
+pub fn forget_ruleset(ptr: *const Ruleset) {
+    with_tracer(|tracer| tracer.rulesets.remove(&(ptr as usize)));
+}
+
+// the number of a context, null if it was created before tracing started
+pub fn context(ptr: *const Context) -> Field {
+    with_tracer(|tracer| tracer.contexts.get(&(ptr as usize)).copied())
+        .flatten()
+        .map(|n| n as u32)
+        .into()
+}
+
+pub fn ruleset(ptr: *const Ruleset) -> Field {
+    with_tracer(|tracer| tracer.rulesets.get(&(ptr as usize)).copied())
+        .flatten()
+        .map(|n| n as u32)
+        .into()
+}
+
+// the numbers of several rulesets, `none` for one created before tracing started
+pub fn rulesets(ptrs: &[*const Ruleset]) -> Field {
+    Field::List(
+        ptrs.iter()
+            .map(|&ptr| match ruleset(ptr) {
+                Field::Str(n) => n,
+                _ => "none".into(),
+            })
+            .collect(),
+    )
+}
+
+pub fn numbers(ns: &[u32]) -> Field {
+    Field::List(ns.iter().map(|n| n.to_string()).collect())
+}
+
+pub unsafe fn c_str(ptr: *const c_char) -> Field {
+    if ptr.is_null() {
+        Field::Null
+    } else {
+        Field::Str(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+pub unsafe fn c_strs(ptr: *const *const c_char, len: u32) -> Field {
+    Field::List(crate::ptr_to_strings(ptr, len))
+}
+
+// name, left, right and direction flag of each rule
+pub unsafe fn ffi_rules(ptr: *const *mut FFIRule, len: u32) -> Field {
+    let rules = std::slice::from_raw_parts(ptr, len as usize);
+    let mut items = vec![];
+    for &rule in rules {
+        let rule = &*rule;
+        for s in [rule.name, rule.left, rule.right] {
+            items.push(CStr::from_ptr(s).to_string_lossy().into_owned());
+        }
+        items.push(rule.bidirectional.to_string());
+    }
+    Field::List(items)
+}
+
+// the size of the egraph and stop reason at each iteration,
+// leaving out timings since they differ from run to run
+pub fn iterations(iterations: &[EGraphIter]) -> Field {
+    Field::List(
+        iterations
+            .iter()
+            .map(|i| format!("{} {} {}", i.numnodes, i.numclasses, i.stop_reason))
+            .collect(),
+    )
+}
+
+pub unsafe fn variants(variants: &[FFIVariant]) -> Field {
+    Field::List(
+        variants
+            .iter()
+            .map(|v| {
+                let op = CStr::from_ptr(v.op).to_string_lossy();
+                let expr = CStr::from_ptr(v.expr).to_string_lossy();
+                format!("{} {} {}", v.cost, op, expr)
+            })
+            .collect(),
+    )
+}
+
+// Records a call when tracing is enabled, only building the fields when it is.
+macro_rules! trace_call {
+    ($call:expr, [$($arg:expr),* $(,)?]) => {
+        if $crate::trace::is_enabled() {
+            $crate::trace::record_call($call, vec![$($crate::trace::Field::from($arg)),*]);
+        }
+    };
+}
+
+macro_rules! trace_result {
+    ($result:expr) => {
+        if $crate::trace::is_enabled() {
+            $crate::trace::record_result($crate::trace::Field::from($result));
+        }
+    };
+}
+
+pub(crate) use {trace_call, trace_result};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(fields: &[Field]) -> Vec<Option<String>> {
+        let line = encode_fields(fields);
+        assert!(!line.contains(['\n', '\r']), "{:?} spans lines", line);
+        decode_line(&line)
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let strings = [
+            "",
+            "(+ $Type x y)",
+            "a\tb",
+            "a\nb\r",
+            "\\",
+            "\\t",
+            "\\0",
+            "\\\\0",
+        ];
+        for s in strings {
+            assert_eq!(round_trip(&[s.into()]), vec![Some(s.to_string())]);
+        }
+    }
+
+    #[test]
+    fn nulls_round_trip() {
+        let fields = [
+            Field::Null,
+            "".into(),
+            None::<u32>.into(),
+            Some(3u32).into(),
+        ];
+        assert_eq!(
+            round_trip(&fields),
+            vec![None, Some("".into()), None, Some("3".into())]
+        );
+    }
+
+    #[test]
+    fn lists_are_prefixed_by_their_length() {
+        let items = vec!["x".to_string(), "a\tb".into(), "".into()];
+        let fields = [
+            "egraph_add_exprs".into(),
+            items.clone().into(),
+            Field::List(vec![]),
+            true.into(),
+        ];
+        let mut expected = vec![Some("egraph_add_exprs".to_string()), Some("3".into())];
+        expected.extend(items.into_iter().map(Some));
+        expected.extend([Some("0".into()), Some("true".into())]);
+        assert_eq!(round_trip(&fields), expected);
+    }
+}